@@ -1,5 +1,5 @@
 import { useState, useEffect, useRef } from 'react';
-import { Row, Col, Typography, Layout, Button, Spin } from 'antd';
+import { Row, Col, Typography, Layout, Button, Spin, Progress } from 'antd';
 import { useFileManager } from '../../../../BoxPeer/src/utils/fileUtils';
 import { Link } from 'react-router-dom';
 import { usePreview } from '../../../../BoxPeer/src/context/PreviewContext';
@@ -12,8 +12,38 @@ interface FileData {
   fileData: Uint8Array;
 }
 
+// A file still arriving: chunks are copied into place as they come in
+interface PartialFile {
+  cid: string;
+  fileData: Uint8Array;
+  received: number;
+}
+
+const BASE32_ALPHABET = 'abcdefghijklmnopqrstuvwxyz234567';
+
+// Renders binary CID bytes in the multibase base32 form used by the backend
+const cidToString = (bytes: Uint8Array) => {
+  let bits = 0;
+  let value = 0;
+  let output = 'b';
+  for (const byte of bytes) {
+    value = (value << 8) | byte;
+    bits += 8;
+    while (bits >= 5) {
+      output += BASE32_ALPHABET[(value >>> (bits - 5)) & 31];
+      bits -= 5;
+    }
+  }
+  if (bits > 0) {
+    output += BASE32_ALPHABET[(value << (5 - bits)) & 31];
+  }
+  return output;
+};
+
 const Contents = () => {
   const [files, setFiles] = useState<FileData[]>([]);
+  // Bytes received and expected for each file still arriving, keyed by transfer id
+  const [progress, setProgress] = useState<Record<string, [number, number]>>({});
   const [loading, setLoading] = useState(true);
   const wsRef = useRef<WebSocket | null>(null);
   const hasFetchedMetadata = useRef(false);
@@ -25,30 +55,87 @@ const Contents = () => {
     const ws = new WebSocket('ws://127.0.0.1:9091/ws');
     wsRef.current = ws;
 
+    ws.binaryType = 'arraybuffer';
+    // Files still arriving, keyed by the transfer id of their frames
+    const partials = new Map<number, PartialFile>();
+
+    const updateProgress = (transfer: number, received?: [number, number]) => {
+      setProgress((prev) => {
+        const next = { ...prev };
+        if (received) {
+          next[transfer] = received;
+        } else {
+          delete next[transfer];
+        }
+        return next;
+      });
+    };
+
     ws.onopen = () => {
       console.log('Connected to WebSocket');
     };
 
     ws.onmessage = (event) => {
-      try {
-        // Expecting a JSON message in the format: { "cid": "Qm123...", "data": "base64-encoded-string" }
-        const message = JSON.parse(event.data);
-        if (message.cid && message.data) {
-          const cid = message.cid;
-          const fileData = new Uint8Array(atob(message.data).split('').map(char => char.charCodeAt(0)));
-
-          // Update the files state only if the CID is unique
-          setFiles((prevFiles) => {
-            const newFile = { cid, fileData };
-            if (!prevFiles.some((file) => file.cid === cid)) {
-              return [...prevFiles, newFile];
+      if (!(event.data instanceof ArrayBuffer)) {
+        // Text frames are JSON control messages: { "type": "FILE" | "DONE" | "ERROR", ... }
+        try {
+          const message = JSON.parse(event.data);
+          if (message.type === 'ERROR') {
+            console.error(`Error ${message.code}${message.cid ? ` for ${message.cid}` : ''}: ${message.message}`);
+            // A file that fails part way is dropped; no more chunks follow
+            if (message.transfer !== undefined) {
+              partials.delete(message.transfer);
+              updateProgress(message.transfer);
             }
-            return prevFiles;
-          });
+          }
+        } catch (error) {
+          console.error('Error parsing message:', error);
         }
-      } catch (error) {
-        console.error('Error parsing message:', error);
+        return;
       }
+
+      // Binary frames: u8 version | u8 flags | u32 transfer | u16 cid length | cid | u32 index | u64 total size | payload
+      const view = new DataView(event.data);
+      if (view.getUint8(0) !== 2) {
+        console.error(`Unsupported frame version ${view.getUint8(0)}`);
+        return;
+      }
+      const isFinal = (view.getUint8(1) & 1) === 1;
+      const transfer = view.getUint32(2);
+      const cidLength = view.getUint16(6);
+      const totalSize = Number(view.getBigUint64(8 + cidLength + 4));
+      const payload = new Uint8Array(event.data, 8 + cidLength + 4 + 8);
+      let partial = partials.get(transfer);
+      if (!partial) {
+        const cid = cidToString(new Uint8Array(event.data, 8, cidLength));
+        partial = { cid, fileData: new Uint8Array(totalSize), received: 0 };
+        partials.set(transfer, partial);
+      }
+      if (partial.received + payload.length > partial.fileData.length) {
+        console.error(`Transfer ${transfer} sent more than its ${totalSize} bytes`);
+        partials.delete(transfer);
+        updateProgress(transfer);
+        return;
+      }
+      partial.fileData.set(payload, partial.received);
+      partial.received += payload.length;
+      if (!isFinal) {
+        updateProgress(transfer, [partial.received, totalSize]);
+        return;
+      }
+
+      partials.delete(transfer);
+      updateProgress(transfer);
+      const { cid, fileData } = partial;
+
+      // Update the files state only if the CID is unique
+      setFiles((prevFiles) => {
+        const newFile = { cid, fileData };
+        if (!prevFiles.some((file) => file.cid === cid)) {
+          return [...prevFiles, newFile];
+        }
+        return prevFiles;
+      });
     };
 
     ws.onclose = (error) => {
@@ -83,7 +170,7 @@ const Contents = () => {
 
       // Send request to WebSocket to fetch files, but only once
       if (wsRef.current && wsRef.current.readyState === WebSocket.OPEN) {
-        wsRef.current.send(`GET_FILES:${cids}`);
+        wsRef.current.send(JSON.stringify({ v: 1, id: 1, type: 'GET_FILES', cids: cids.split(',') }));
         hasSentRequest.current = true; // Mark request as sent to avoid duplicate requests
       } else {
         console.error('WebSocket connection is not open.');
@@ -107,6 +194,9 @@ const Contents = () => {
         <Title level={2} style={{ marginTop: '80px', marginBottom: '40px' }}>
           Available Content in the Network
         </Title>
+        {Object.entries(progress).map(([transfer, [received, total]]) => (
+          <Progress key={transfer} percent={Math.floor((received / total) * 100)} size="small" />
+        ))}
         <Row gutter={[8, 8]} justify="center">
           {loading ? (
             <Spin />
@@ -0,0 +1,194 @@
+use cid::Cid;
+use lru::LruCache;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Mutex;
+
+/// Memory given to the block cache when none is configured.
+pub const DEFAULT_CACHE_SIZE: usize = 64 * 1024 * 1024;
+
+/// Recently served blocks kept in memory, evicting the least recently used
+/// ones once their total size exceeds the capacity.
+pub struct BlockCache {
+    capacity: usize,
+    entries: Mutex<Entries>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+struct Entries {
+    blocks: LruCache<Cid, Vec<u8>>,
+    bytes: usize,
+}
+
+/// Counters of a `BlockCache` at one point in time.
+#[derive(Clone, Copy, Debug)]
+pub struct CacheStats {
+    pub capacity: usize,
+    pub bytes: usize,
+    pub blocks: usize,
+    pub hits: u64,
+    pub misses: u64,
+}
+
+impl CacheStats {
+    /// Share of lookups answered from memory, 0 before the first lookup.
+    pub fn hit_rate(&self) -> f64 {
+        let lookups = self.hits + self.misses;
+        if lookups == 0 {
+            0.0
+        } else {
+            self.hits as f64 / lookups as f64
+        }
+    }
+}
+
+impl BlockCache {
+    /// A cache holding up to `capacity` bytes of blocks; 0 disables it.
+    pub fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            entries: Mutex::new(Entries {
+                blocks: LruCache::unbounded(),
+                bytes: 0,
+            }),
+            hits: AtomicU64::new(0),
+            misses: AtomicU64::new(0),
+        }
+    }
+
+    pub fn get(&self, cid: &Cid) -> Option<Vec<u8>> {
+        let block = self.lock().blocks.get(cid).cloned();
+        let counter = if block.is_some() {
+            &self.hits
+        } else {
+            &self.misses
+        };
+        counter.fetch_add(1, Ordering::Relaxed);
+        block
+    }
+
+    /// Adds a block, evicting older ones to make room. Blocks larger than
+    /// the whole cache are not kept.
+    pub fn insert(&self, cid: Cid, block: Vec<u8>) {
+        if self.capacity == 0 || block.len() > self.capacity {
+            return;
+        }
+        let mut entries = self.lock();
+        entries.bytes += block.len();
+        if let Some(old) = entries.blocks.put(cid, block) {
+            entries.bytes -= old.len();
+        }
+        while entries.bytes > self.capacity {
+            match entries.blocks.pop_lru() {
+                Some((_, evicted)) => entries.bytes -= evicted.len(),
+                None => break,
+            }
+        }
+    }
+
+    pub fn remove(&self, cid: &Cid) {
+        let mut entries = self.lock();
+        if let Some(block) = entries.blocks.pop(cid) {
+            entries.bytes -= block.len();
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let entries = self.lock();
+        CacheStats {
+            capacity: self.capacity,
+            bytes: entries.bytes,
+            blocks: entries.blocks.len(),
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+        }
+    }
+
+    fn lock(&self) -> std::sync::MutexGuard<'_, Entries> {
+        // The entries stay consistent even if a holder panicked
+        self.entries.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::{block_cid, RAW_CODEC};
+
+    fn cid(n: u8) -> Cid {
+        block_cid(RAW_CODEC, &[n])
+    }
+
+    #[test]
+    fn counts_bytes_of_inserted_blocks() {
+        let cache = BlockCache::new(100);
+        cache.insert(cid(1), vec![0; 30]);
+        cache.insert(cid(2), vec![0; 20]);
+        let stats = cache.stats();
+        assert_eq!((stats.bytes, stats.blocks), (50, 2));
+    }
+
+    #[test]
+    fn replacing_a_block_counts_it_once() {
+        let cache = BlockCache::new(100);
+        cache.insert(cid(1), vec![0; 30]);
+        cache.insert(cid(1), vec![0; 40]);
+        let stats = cache.stats();
+        assert_eq!((stats.bytes, stats.blocks), (40, 1));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_over_capacity() {
+        let cache = BlockCache::new(100);
+        cache.insert(cid(1), vec![0; 40]);
+        cache.insert(cid(2), vec![0; 40]);
+        assert!(cache.get(&cid(1)).is_some());
+        cache.insert(cid(3), vec![0; 40]);
+        assert!(cache.get(&cid(2)).is_none());
+        assert!(cache.get(&cid(1)).is_some());
+        assert!(cache.get(&cid(3)).is_some());
+        let stats = cache.stats();
+        assert_eq!((stats.bytes, stats.blocks), (80, 2));
+    }
+
+    #[test]
+    fn skips_blocks_larger_than_the_cache() {
+        let cache = BlockCache::new(100);
+        cache.insert(cid(1), vec![0; 50]);
+        cache.insert(cid(2), vec![0; 101]);
+        assert!(cache.get(&cid(2)).is_none());
+        assert_eq!(cache.stats().bytes, 50);
+    }
+
+    #[test]
+    fn remove_releases_bytes() {
+        let cache = BlockCache::new(100);
+        cache.insert(cid(1), vec![0; 30]);
+        cache.remove(&cid(1));
+        cache.remove(&cid(2));
+        let stats = cache.stats();
+        assert_eq!((stats.bytes, stats.blocks), (0, 0));
+    }
+
+    #[test]
+    fn zero_capacity_disables_cache() {
+        let cache = BlockCache::new(0);
+        cache.insert(cid(1), Vec::new());
+        assert!(cache.get(&cid(1)).is_none());
+        assert_eq!(cache.stats().blocks, 0);
+    }
+
+    #[test]
+    fn counts_hits_and_misses() {
+        let cache = BlockCache::new(100);
+        assert_eq!(cache.stats().hit_rate(), 0.0);
+        cache.insert(cid(1), vec![0; 10]);
+        cache.get(&cid(1));
+        cache.get(&cid(1));
+        cache.get(&cid(1));
+        cache.get(&cid(2));
+        let stats = cache.stats();
+        assert_eq!((stats.hits, stats.misses), (3, 1));
+        assert_eq!(stats.hit_rate(), 0.75);
+    }
+}
@@ -0,0 +1,566 @@
+use anyhow::{anyhow, bail, Context, Result};
+use clap::{Parser, Subcommand};
+use libp2p::Multiaddr;
+use serde::Deserialize;
+use std::fs;
+use std::net::SocketAddr;
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing_subscriber::EnvFilter;
+
+use crate::cache::DEFAULT_CACHE_SIZE;
+use crate::dag;
+use crate::net::{
+    NetworkConfig, ReprovideConfig, ReprovideStrategy, DEFAULT_FETCH_TIMEOUT,
+    DEFAULT_MAX_UPLOAD_SIZE,
+};
+use crate::node;
+
+/// Config file picked up from the working directory when `--config` is not given.
+const DEFAULT_CONFIG_FILE: &str = "boxpeer.toml";
+
+const DEFAULT_LISTEN_ADDR: &str = "/ip4/0.0.0.0/udp/9090/quic-v1";
+const DEFAULT_BOOTSTRAP_PEER: &str = "/ip4/203.161.57.50/udp/9090/quic-v1";
+const DEFAULT_HTTP_BIND: &str = "127.0.0.1:9090";
+const DEFAULT_WORKERS: usize = 2;
+const DEFAULT_LOG_LEVEL: &str = "info";
+const DEFAULT_MAX_CONCURRENT_FETCHES: usize = 8;
+const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_GC_INTERVAL: Duration = Duration::from_secs(600);
+const DEFAULT_REPROVIDE_INTERVAL: Duration = Duration::from_secs(12 * 60 * 60);
+const DEFAULT_REPROVIDE_BATCH_SIZE: usize = 16;
+
+/// Largest leaf block other peers are expected to accept over bitswap.
+const MAX_CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Command line flags. Every setting can also come from the environment
+/// (flags win) and from the TOML config file (the environment wins).
+#[derive(Parser, Debug)]
+#[command(name = "BoxPeer_Web", version, about = "BoxPeer web gateway node")]
+pub struct Cli {
+    /// TOML config file, defaults to ./boxpeer.toml when present
+    #[arg(long, env = "BOXPEER_CONFIG")]
+    config: Option<PathBuf>,
+
+    /// libp2p listen addresses, comma separated
+    #[arg(long = "listen", env = "BOXPEER_LISTEN", value_delimiter = ',')]
+    listen_addrs: Option<Vec<String>>,
+
+    /// HTTP and WebSocket bind address
+    #[arg(long, env = "BOXPEER_HTTP_BIND")]
+    http_bind: Option<String>,
+
+    /// Bootstrap peer addresses, comma separated
+    #[arg(long = "bootstrap", env = "BOXPEER_BOOTSTRAP", value_delimiter = ',')]
+    bootstrap_peers: Option<Vec<String>>,
+
+    /// Directory holding the blockstore and node identity
+    #[arg(long, env = "BOXPEER_DATA_DIR")]
+    data_dir: Option<PathBuf>,
+
+    /// HTTP server worker threads
+    #[arg(long, env = "BOXPEER_WORKERS")]
+    workers: Option<usize>,
+
+    /// Log filter, e.g. `info` or `BoxPeer_Web=debug,libp2p=warn`
+    #[arg(long, env = "BOXPEER_LOG_LEVEL")]
+    log_level: Option<String>,
+
+    /// CIDs fetched in parallel per GET_FILES request
+    #[arg(long, env = "BOXPEER_MAX_CONCURRENT_FETCHES")]
+    max_concurrent_fetches: Option<usize>,
+
+    /// Seconds to wait for the network before a fetch fails
+    #[arg(long, env = "BOXPEER_FETCH_TIMEOUT_SECS")]
+    fetch_timeout_secs: Option<u64>,
+
+    /// Leaf block size in bytes for imported files
+    #[arg(long, env = "BOXPEER_CHUNK_SIZE")]
+    chunk_size: Option<usize>,
+
+    /// MiB of recently read blocks kept in memory, 0 disables the cache
+    #[arg(long, env = "BOXPEER_CACHE_SIZE_MB")]
+    cache_size_mb: Option<usize>,
+
+    /// MiB the blockstore may hold before unpinned blocks are evicted,
+    /// unbounded when unset
+    #[arg(long, env = "BOXPEER_STORAGE_QUOTA_MB")]
+    storage_quota_mb: Option<u64>,
+
+    /// Seconds between garbage collection passes while a quota is set
+    #[arg(long, env = "BOXPEER_GC_INTERVAL_SECS")]
+    gc_interval_secs: Option<u64>,
+
+    /// Seconds between re-announcements of stored content, 0 disables them
+    /// (provider records expire after 48 hours)
+    #[arg(long, env = "BOXPEER_REPROVIDE_INTERVAL_SECS")]
+    reprovide_interval_secs: Option<u64>,
+
+    /// Content re-announced: the roots of `pinned` DAGs or of `all` stored ones
+    #[arg(long, env = "BOXPEER_REPROVIDE_STRATEGY")]
+    reprovide_strategy: Option<String>,
+
+    /// Announcements the reprovider sends at once
+    #[arg(long, env = "BOXPEER_REPROVIDE_BATCH_SIZE")]
+    reprovide_batch_size: Option<usize>,
+
+    /// Bearer token required for uploads, uploads are disabled when unset
+    #[arg(long, env = "BOXPEER_UPLOAD_TOKEN", hide_env_values = true)]
+    upload_token: Option<String>,
+
+    /// MiB of the largest file accepted for upload
+    #[arg(long, env = "BOXPEER_MAX_UPLOAD_SIZE_MB")]
+    max_upload_size_mb: Option<u64>,
+
+    /// Seconds in-flight requests get to finish on shutdown
+    #[arg(long, env = "BOXPEER_SHUTDOWN_TIMEOUT_SECS")]
+    shutdown_timeout_secs: Option<u64>,
+
+    #[command(subcommand)]
+    pub command: Option<CliCommand>,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum CliCommand {
+    /// Manage the node identity
+    Identity {
+        #[command(subcommand)]
+        action: IdentityAction,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum IdentityAction {
+    /// Write the node keypair to a file
+    Export { file: PathBuf },
+    /// Replace the node keypair with the one in a file
+    Import { file: PathBuf },
+}
+
+/// Settings read from the TOML config file.
+#[derive(Deserialize, Default, Debug)]
+#[serde(default, deny_unknown_fields)]
+struct FileConfig {
+    listen_addrs: Option<Vec<String>>,
+    http_bind: Option<String>,
+    bootstrap_peers: Option<Vec<String>>,
+    data_dir: Option<PathBuf>,
+    workers: Option<usize>,
+    log_level: Option<String>,
+    max_concurrent_fetches: Option<usize>,
+    fetch_timeout_secs: Option<u64>,
+    chunk_size: Option<usize>,
+    cache_size_mb: Option<usize>,
+    storage_quota_mb: Option<u64>,
+    gc_interval_secs: Option<u64>,
+    reprovide_interval_secs: Option<u64>,
+    reprovide_strategy: Option<String>,
+    reprovide_batch_size: Option<usize>,
+    upload_token: Option<String>,
+    max_upload_size_mb: Option<u64>,
+    shutdown_timeout_secs: Option<u64>,
+}
+
+/// Validated settings of the server binary.
+pub struct Config {
+    pub network: NetworkConfig,
+    pub http_bind: SocketAddr,
+    pub workers: usize,
+    pub log_filter: EnvFilter,
+    pub max_concurrent_fetches: usize,
+    pub fetch_timeout: Duration,
+    pub chunk_size: usize,
+    /// Bytes of blocks kept in memory.
+    pub cache_size: usize,
+    /// Bytes the blockstore may hold, unbounded when `None`.
+    pub storage_quota: Option<u64>,
+    pub gc_interval: Duration,
+    /// Reprovider settings, `None` when it is disabled.
+    pub reprovide: Option<ReprovideConfig>,
+    pub upload_token: Option<String>,
+    /// Bytes of the largest file accepted for upload.
+    pub max_upload_size: u64,
+    pub shutdown_timeout: Duration,
+}
+
+impl Config {
+    /// Layers defaults, the config file, the environment and the command line
+    /// and validates the result.
+    pub fn load(cli: Cli) -> Result<Self> {
+        let file = match &cli.config {
+            Some(path) => read_config_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                read_config_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => FileConfig::default(),
+        };
+
+        let listen_addrs = parse_multiaddrs(
+            "listen address",
+            cli.listen_addrs
+                .or(file.listen_addrs)
+                .unwrap_or_else(|| vec![DEFAULT_LISTEN_ADDR.to_string()]),
+        )?;
+        if listen_addrs.is_empty() {
+            bail!("At least one listen address is required");
+        }
+        let bootstrap_peers = parse_multiaddrs(
+            "bootstrap peer",
+            cli.bootstrap_peers
+                .or(file.bootstrap_peers)
+                .unwrap_or_else(|| vec![DEFAULT_BOOTSTRAP_PEER.to_string()]),
+        )?;
+
+        let http_bind = cli
+            .http_bind
+            .or(file.http_bind)
+            .unwrap_or_else(|| DEFAULT_HTTP_BIND.to_string());
+        let http_bind = http_bind
+            .parse()
+            .map_err(|e| anyhow!("Invalid HTTP bind address {:?}: {}", http_bind, e))?;
+
+        let workers = cli.workers.or(file.workers).unwrap_or(DEFAULT_WORKERS);
+        if workers == 0 {
+            bail!("workers must be at least 1");
+        }
+
+        let log_level = cli
+            .log_level
+            .or(file.log_level)
+            .unwrap_or_else(|| DEFAULT_LOG_LEVEL.to_string());
+        let log_filter = EnvFilter::try_new(&log_level)
+            .map_err(|e| anyhow!("Invalid log level {:?}: {}", log_level, e))?;
+
+        let max_concurrent_fetches = cli
+            .max_concurrent_fetches
+            .or(file.max_concurrent_fetches)
+            .unwrap_or(DEFAULT_MAX_CONCURRENT_FETCHES);
+        if max_concurrent_fetches == 0 {
+            bail!("max_concurrent_fetches must be at least 1");
+        }
+
+        let fetch_timeout = match cli.fetch_timeout_secs.or(file.fetch_timeout_secs) {
+            Some(0) => bail!("fetch_timeout_secs must be at least 1"),
+            Some(secs) => Duration::from_secs(secs),
+            None => DEFAULT_FETCH_TIMEOUT,
+        };
+
+        let chunk_size = cli
+            .chunk_size
+            .or(file.chunk_size)
+            .unwrap_or(dag::DEFAULT_CHUNK_SIZE);
+        if chunk_size == 0 || chunk_size > MAX_CHUNK_SIZE {
+            bail!(
+                "chunk_size must be between 1 and {} bytes, got {}",
+                MAX_CHUNK_SIZE,
+                chunk_size
+            );
+        }
+
+        let cache_size = match cli.cache_size_mb.or(file.cache_size_mb) {
+            Some(mb) => mb
+                .checked_mul(1024 * 1024)
+                .ok_or_else(|| anyhow!("cache_size_mb is too large: {}", mb))?,
+            None => DEFAULT_CACHE_SIZE,
+        };
+
+        let storage_quota = match cli.storage_quota_mb.or(file.storage_quota_mb) {
+            Some(mb) => Some(
+                mb.checked_mul(1024 * 1024)
+                    .ok_or_else(|| anyhow!("storage_quota_mb is too large: {}", mb))?,
+            ),
+            None => None,
+        };
+
+        let gc_interval = match cli.gc_interval_secs.or(file.gc_interval_secs) {
+            Some(0) => bail!("gc_interval_secs must be at least 1"),
+            Some(secs) => Duration::from_secs(secs),
+            None => DEFAULT_GC_INTERVAL,
+        };
+
+        let strategy = match cli.reprovide_strategy.or(file.reprovide_strategy) {
+            Some(strategy) => strategy
+                .parse()
+                .map_err(|e| anyhow!("Invalid reprovide strategy: {}", e))?,
+            None => ReprovideStrategy::default(),
+        };
+        let batch_size = cli
+            .reprovide_batch_size
+            .or(file.reprovide_batch_size)
+            .unwrap_or(DEFAULT_REPROVIDE_BATCH_SIZE);
+        if batch_size == 0 {
+            bail!("reprovide_batch_size must be at least 1");
+        }
+        let reprovide = match cli.reprovide_interval_secs.or(file.reprovide_interval_secs) {
+            Some(0) => None,
+            secs => Some(ReprovideConfig {
+                strategy,
+                interval: secs.map_or(DEFAULT_REPROVIDE_INTERVAL, Duration::from_secs),
+                batch_size,
+            }),
+        };
+
+        let shutdown_timeout = cli
+            .shutdown_timeout_secs
+            .or(file.shutdown_timeout_secs)
+            .map(Duration::from_secs)
+            .unwrap_or(DEFAULT_SHUTDOWN_TIMEOUT);
+
+        let upload_token = cli
+            .upload_token
+            .or(file.upload_token)
+            .filter(|token| !token.is_empty());
+
+        let max_upload_size = match cli.max_upload_size_mb.or(file.max_upload_size_mb) {
+            Some(0) => bail!("max_upload_size_mb must be at least 1"),
+            Some(mb) => mb
+                .checked_mul(1024 * 1024)
+                .ok_or_else(|| anyhow!("max_upload_size_mb is too large: {}", mb))?,
+            None => DEFAULT_MAX_UPLOAD_SIZE,
+        };
+
+        Ok(Config {
+            network: NetworkConfig {
+                data_dir: cli
+                    .data_dir
+                    .or(file.data_dir)
+                    .unwrap_or_else(node::default_data_dir),
+                listen_addrs,
+                bootstrap_peers,
+                republish_providers: reprovide.is_none(),
+            },
+            http_bind,
+            workers,
+            log_filter,
+            max_concurrent_fetches,
+            fetch_timeout,
+            chunk_size,
+            cache_size,
+            storage_quota,
+            gc_interval,
+            reprovide,
+            upload_token,
+            max_upload_size,
+            shutdown_timeout,
+        })
+    }
+}
+
+fn read_config_file(path: &Path) -> Result<FileConfig> {
+    let contents = fs::read_to_string(path)
+        .with_context(|| format!("Failed to read config file {}", path.display()))?;
+    toml::from_str(&contents).with_context(|| format!("Invalid config file {}", path.display()))
+}
+
+fn parse_multiaddrs(what: &str, addrs: Vec<String>) -> Result<Vec<Multiaddr>> {
+    addrs
+        .iter()
+        .map(|s| s.trim())
+        .filter(|s| !s.is_empty())
+        .map(|s| {
+            s.parse()
+                .map_err(|e| anyhow!("Invalid {} {:?}: {}", what, s, e))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::Mutex;
+
+    /// Held while a config is parsed, so a test setting a `BOXPEER_*`
+    /// variable does not leak it into the others running in parallel.
+    static ENV: Mutex<()> = Mutex::new(());
+
+    /// Loads the config from `args` with `toml` as the config file, so a
+    /// `boxpeer.toml` in the working directory is never picked up.
+    fn load(name: &str, toml: &str, args: &[&str]) -> Result<Config> {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        load_locked(name, toml, args)
+    }
+
+    fn load_locked(name: &str, toml: &str, args: &[&str]) -> Result<Config> {
+        let path = std::env::temp_dir().join(format!(
+            "boxpeer-config-test-{}-{}.toml",
+            std::process::id(),
+            name
+        ));
+        fs::write(&path, toml).unwrap();
+        let config_arg = path.to_str().unwrap();
+        let cli = Cli::try_parse_from(["BoxPeer_Web", "--config", config_arg].iter().chain(args))
+            .unwrap();
+        let config = Config::load(cli);
+        fs::remove_file(&path).unwrap();
+        config
+    }
+
+    fn load_err(name: &str, toml: &str, args: &[&str]) -> String {
+        match load(name, toml, args) {
+            Ok(_) => panic!("{} loaded", name),
+            Err(e) => format!("{:#}", e),
+        }
+    }
+
+    #[test]
+    fn defaults() {
+        let config = load("defaults", "", &[]).unwrap();
+        assert_eq!(config.http_bind, DEFAULT_HTTP_BIND.parse().unwrap());
+        assert_eq!(
+            config.network.listen_addrs,
+            [DEFAULT_LISTEN_ADDR.parse::<Multiaddr>().unwrap()]
+        );
+        assert_eq!(config.workers, DEFAULT_WORKERS);
+        assert_eq!(config.chunk_size, dag::DEFAULT_CHUNK_SIZE);
+        assert_eq!(config.cache_size, DEFAULT_CACHE_SIZE);
+        assert_eq!(config.storage_quota, None);
+        assert_eq!(config.fetch_timeout, DEFAULT_FETCH_TIMEOUT);
+        assert_eq!(config.max_upload_size, DEFAULT_MAX_UPLOAD_SIZE);
+        assert_eq!(config.upload_token, None);
+        let reprovide = config.reprovide.unwrap();
+        assert_eq!(reprovide.strategy, ReprovideStrategy::Pinned);
+        assert_eq!(reprovide.interval, DEFAULT_REPROVIDE_INTERVAL);
+        assert!(!config.network.republish_providers);
+    }
+
+    #[test]
+    fn file_overrides_defaults() {
+        let config = load(
+            "file",
+            r#"
+                http_bind = "127.0.0.1:8000"
+                workers = 4
+                cache_size_mb = 2
+                storage_quota_mb = 10
+                reprovide_strategy = "all"
+                upload_token = "secret"
+            "#,
+            &[],
+        )
+        .unwrap();
+        assert_eq!(config.http_bind, "127.0.0.1:8000".parse().unwrap());
+        assert_eq!(config.workers, 4);
+        assert_eq!(config.cache_size, 2 * 1024 * 1024);
+        assert_eq!(config.storage_quota, Some(10 * 1024 * 1024));
+        assert_eq!(config.reprovide.unwrap().strategy, ReprovideStrategy::All);
+        assert_eq!(config.upload_token.as_deref(), Some("secret"));
+    }
+
+    #[test]
+    fn flags_override_file() {
+        let config = load(
+            "flags",
+            "workers = 4\nchunk_size = 1024\nbootstrap_peers = [\"/ip4/1.2.3.4/udp/1/quic-v1\"]",
+            &["--workers", "8", "--bootstrap", ""],
+        )
+        .unwrap();
+        assert_eq!(config.workers, 8);
+        assert_eq!(config.chunk_size, 1024);
+        assert!(config.network.bootstrap_peers.is_empty());
+    }
+
+    #[test]
+    fn env_overrides_file_and_flags_override_env() {
+        let _env = ENV.lock().unwrap_or_else(|e| e.into_inner());
+        std::env::set_var("BOXPEER_WORKERS", "6");
+        std::env::set_var("BOXPEER_CHUNK_SIZE", "2048");
+        let config = load_locked(
+            "env",
+            "workers = 4\nchunk_size = 1024\ncache_size_mb = 3",
+            &["--chunk-size", "4096"],
+        );
+        std::env::remove_var("BOXPEER_WORKERS");
+        std::env::remove_var("BOXPEER_CHUNK_SIZE");
+        let config = config.unwrap();
+        assert_eq!(config.workers, 6);
+        assert_eq!(config.chunk_size, 4096);
+        assert_eq!(config.cache_size, 3 * 1024 * 1024);
+    }
+
+    #[test]
+    fn zero_disables_reprovider() {
+        let config = load("reprovide", "", &["--reprovide-interval-secs", "0"]).unwrap();
+        assert!(config.reprovide.is_none());
+        assert!(config.network.republish_providers);
+    }
+
+    #[test]
+    fn empty_upload_token_disables_uploads() {
+        let config = load(
+            "token",
+            "upload_token = \"secret\"",
+            &["--upload-token", ""],
+        )
+        .unwrap();
+        assert_eq!(config.upload_token, None);
+    }
+
+    #[test]
+    fn rejects_invalid_values() {
+        for (name, toml, message) in [
+            ("workers", "workers = 0", "workers must be at least 1"),
+            (
+                "fetches",
+                "max_concurrent_fetches = 0",
+                "max_concurrent_fetches must be at least 1",
+            ),
+            (
+                "timeout",
+                "fetch_timeout_secs = 0",
+                "fetch_timeout_secs must be at least 1",
+            ),
+            ("chunk-zero", "chunk_size = 0", "chunk_size must be between"),
+            (
+                "chunk-large",
+                "chunk_size = 2000000",
+                "chunk_size must be between",
+            ),
+            (
+                "gc",
+                "gc_interval_secs = 0",
+                "gc_interval_secs must be at least 1",
+            ),
+            (
+                "batch",
+                "reprovide_batch_size = 0",
+                "reprovide_batch_size must be at least 1",
+            ),
+            (
+                "upload",
+                "max_upload_size_mb = 0",
+                "max_upload_size_mb must be at least 1",
+            ),
+            (
+                "quota",
+                "storage_quota_mb = 9223372036854775807",
+                "storage_quota_mb is too large",
+            ),
+            ("listen", "listen_addrs = []", "At least one listen address"),
+            (
+                "addr",
+                "listen_addrs = [\"nope\"]",
+                "Invalid listen address",
+            ),
+            ("bind", "http_bind = \"nope\"", "Invalid HTTP bind address"),
+            (
+                "strategy",
+                "reprovide_strategy = \"some\"",
+                "Invalid reprovide strategy",
+            ),
+            ("unknown", "colour = \"blue\"", "Invalid config file"),
+        ] {
+            let error = load_err(name, toml, &[]);
+            assert!(error.contains(message), "{}: {}", name, error);
+        }
+    }
+
+    #[test]
+    fn missing_config_file_is_an_error() {
+        let cli =
+            Cli::try_parse_from(["BoxPeer_Web", "--config", "/nonexistent/boxpeer.toml"]).unwrap();
+        match Config::load(cli) {
+            Ok(_) => panic!("loaded a missing config file"),
+            Err(e) => assert!(e.to_string().contains("Failed to read config file")),
+        }
+    }
+}
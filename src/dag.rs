@@ -0,0 +1,715 @@
+use anyhow::{anyhow, bail, Result};
+use cid::Cid;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use libipld::pb::{PbLink, PbNode};
+use multihash_codetable::{Code, MultihashDigest};
+
+/// Multicodec of raw leaf blocks.
+pub const RAW_CODEC: u64 = 0x55;
+/// Multicodec of dag-pb link nodes.
+pub const DAG_PB_CODEC: u64 = 0x70;
+
+/// Leaf size used when none is configured, matching other IPFS importers.
+pub const DEFAULT_CHUNK_SIZE: usize = 256 * 1024;
+
+/// Fan-out of link nodes, the same as the balanced layout of other importers
+/// so a full node stays well below the usual 1 MiB block limit.
+const MAX_LINKS_PER_NODE: usize = 174;
+
+/// UnixFS `DataType::File`.
+const UNIXFS_FILE: u64 = 2;
+
+pub fn block_cid(codec: u64, data: &[u8]) -> Cid {
+    Cid::new_v1(codec, Code::Sha2_256.digest(data))
+}
+
+/// Whether `data` hashes to the digest in `cid`. Blocks hashed with a
+/// function this node cannot compute are taken as they are.
+pub fn block_matches(cid: &Cid, data: &[u8]) -> bool {
+    match Code::try_from(cid.hash().code()) {
+        Ok(code) => code.digest(data) == *cid.hash(),
+        Err(_) => true,
+    }
+}
+
+/// A child of a link node as it is written into the parent.
+struct Link {
+    cid: Cid,
+    /// Bytes of file content below the link.
+    file_size: u64,
+    /// Bytes of encoded blocks below the link, including the linked block.
+    dag_size: u64,
+}
+
+/// Splits a file into raw leaf blocks of `chunk_size` bytes and links them
+/// under dag-pb/UnixFS nodes. Content is fed incrementally and every block is
+/// handed back as soon as it is complete, so only one leaf is buffered.
+pub struct FileImporter {
+    chunk_size: usize,
+    buffer: Vec<u8>,
+    leaves: Vec<Link>,
+}
+
+impl FileImporter {
+    pub fn new(chunk_size: usize) -> Self {
+        let chunk_size = chunk_size.max(1);
+        Self {
+            chunk_size,
+            buffer: Vec::with_capacity(chunk_size),
+            leaves: Vec::new(),
+        }
+    }
+
+    /// Feeds file content, returning the leaf blocks it completed.
+    pub fn push(&mut self, mut data: &[u8]) -> Vec<(Cid, Vec<u8>)> {
+        let mut blocks = Vec::new();
+        while !data.is_empty() {
+            let take = (self.chunk_size - self.buffer.len()).min(data.len());
+            self.buffer.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.buffer.len() == self.chunk_size {
+                blocks.push(self.flush_leaf());
+            }
+        }
+        blocks
+    }
+
+    /// Flushes the last leaf and builds the link nodes above the leaves.
+    /// Returns the root CID together with the blocks not yet handed out.
+    /// A file that fits in one leaf is its own root.
+    pub fn finish(mut self) -> (Cid, Vec<(Cid, Vec<u8>)>) {
+        let mut blocks = Vec::new();
+        if !self.buffer.is_empty() || self.leaves.is_empty() {
+            blocks.push(self.flush_leaf());
+        }
+
+        let mut level = self.leaves;
+        while level.len() > 1 {
+            let mut parents = Vec::with_capacity(level.len().div_ceil(MAX_LINKS_PER_NODE));
+            let mut children = level.into_iter().peekable();
+            while children.peek().is_some() {
+                let group: Vec<Link> = children.by_ref().take(MAX_LINKS_PER_NODE).collect();
+                let (link, block) = link_node(group);
+                blocks.push((link.cid, block));
+                parents.push(link);
+            }
+            level = parents;
+        }
+
+        (level[0].cid, blocks)
+    }
+
+    fn flush_leaf(&mut self) -> (Cid, Vec<u8>) {
+        let data = std::mem::replace(&mut self.buffer, Vec::with_capacity(self.chunk_size));
+        let cid = block_cid(RAW_CODEC, &data);
+        self.leaves.push(Link {
+            cid,
+            file_size: data.len() as u64,
+            dag_size: data.len() as u64,
+        });
+        (cid, data)
+    }
+}
+
+fn link_node(children: Vec<Link>) -> (Link, Vec<u8>) {
+    let file_size = children.iter().map(|c| c.file_size).sum();
+    let unixfs = UnixFsData {
+        data: Vec::new(),
+        file_size: Some(file_size),
+        block_sizes: children.iter().map(|c| c.file_size).collect(),
+    };
+    let node = PbNode {
+        links: children
+            .iter()
+            .map(|c| PbLink {
+                cid: to_ipld_cid(&c.cid),
+                name: Some(String::new()),
+                size: Some(c.dag_size),
+            })
+            .collect(),
+        data: Some(unixfs.encode().into()),
+    };
+    let block = node.into_bytes().into_vec();
+    let cid = block_cid(DAG_PB_CODEC, &block);
+    let dag_size = block.len() as u64 + children.iter().map(|c| c.dag_size).sum::<u64>();
+    (
+        Link {
+            cid,
+            file_size,
+            dag_size,
+        },
+        block,
+    )
+}
+
+/// A decoded block of a file DAG.
+pub enum DagNode {
+    /// Raw leaf, the block is file content.
+    Leaf(Vec<u8>),
+    /// Link node: inline content followed by the content of each child.
+    File {
+        data: Vec<u8>,
+        children: Vec<DagChild>,
+        /// Inline content plus the sizes the children claim, checked not
+        /// to overflow and to match the node's own `filesize`.
+        file_size: u64,
+    },
+}
+
+pub struct DagChild {
+    pub cid: Cid,
+    /// Bytes of file content below the child.
+    pub file_size: u64,
+}
+
+/// A child of a link node holding part of a requested range, with the bytes
+/// to read from it counted from the start of the child's own content.
+pub struct ChildRange {
+    pub child: DagChild,
+    pub start: u64,
+    pub end: u64,
+}
+
+/// The children of a link node that overlap bytes `start..end` of its
+/// content, which begins with `inline_len` bytes of inline data.
+pub fn children_in_range(
+    inline_len: u64,
+    children: Vec<DagChild>,
+    start: u64,
+    end: u64,
+) -> Vec<ChildRange> {
+    let mut offset = inline_len;
+    let mut wanted = Vec::new();
+    for child in children {
+        // Decoding checked the sizes of a node add up without overflow
+        let child_end = offset + child.file_size;
+        if child.file_size > 0 && child_end > start && offset < end {
+            wanted.push(ChildRange {
+                start: start.saturating_sub(offset),
+                end: end.min(child_end) - offset,
+                child,
+            });
+        }
+        offset = child_end;
+    }
+    wanted
+}
+
+/// Bytes `start..end` of the file below `node` in order, one block at a
+/// time. `load` fetches a child, at most `concurrency` ahead of the consumer
+/// and only while it keeps reading. Leaves are sliced exactly and every
+/// child is checked to hold what its parent claims, so the parts add up to
+/// `end - start` bytes.
+pub fn read_range<L>(
+    node: DagNode,
+    start: u64,
+    end: u64,
+    concurrency: usize,
+    load: L,
+) -> BoxStream<'static, Result<Vec<u8>>>
+where
+    L: Fn(&DagChild) -> BoxFuture<'static, Result<DagNode>> + Clone + Send + Sync + 'static,
+{
+    let (data, children) = match node {
+        DagNode::Leaf(data) => {
+            let part = data
+                .get(start as usize..end as usize)
+                .map(|range| range.to_vec())
+                .ok_or_else(|| anyhow!("Leaf block is shorter than its parent claims"));
+            return futures::stream::once(futures::future::ready(part)).boxed();
+        }
+        DagNode::File { data, children, .. } => (data, children),
+    };
+
+    let inline_end = data.len() as u64;
+    let inline = (start < inline_end)
+        .then(|| Ok(data[start as usize..end.min(inline_end) as usize].to_vec()));
+
+    let wanted = children_in_range(inline_end, children, start, end);
+
+    let children = futures::stream::iter(wanted)
+        .map(move |range| {
+            let load = load.clone();
+            async move {
+                let node = load(&range.child).await?;
+                if node.file_size() != range.child.file_size {
+                    return Err(anyhow!(
+                        "Block {} holds {} bytes but its parent claims {}",
+                        range.child.cid,
+                        node.file_size(),
+                        range.child.file_size
+                    ));
+                }
+                Ok(read_range(node, range.start, range.end, concurrency, load))
+            }
+        })
+        .buffered(concurrency)
+        .try_flatten();
+    futures::stream::iter(inline).chain(children).boxed()
+}
+
+impl DagNode {
+    pub fn decode(cid: &Cid, block: Vec<u8>) -> Result<Self> {
+        match cid.codec() {
+            RAW_CODEC => Ok(DagNode::Leaf(block)),
+            DAG_PB_CODEC => {
+                let node = PbNode::from_bytes(block.into())
+                    .map_err(|e| anyhow!("Invalid dag-pb block {}: {:?}", cid, e))?;
+                let unixfs = match node.data.as_deref() {
+                    Some(data) => UnixFsData::decode(data)?,
+                    None => bail!("dag-pb block {} carries no UnixFS data", cid),
+                };
+                if unixfs.block_sizes.len() != node.links.len() {
+                    bail!("dag-pb block {} has mismatched block sizes", cid);
+                }
+                let file_size = unixfs
+                    .block_sizes
+                    .iter()
+                    .try_fold(unixfs.data.len() as u64, |total, size| {
+                        total.checked_add(*size)
+                    })
+                    .ok_or_else(|| anyhow!("dag-pb block {} claims more than 2^64 bytes", cid))?;
+                if let Some(claimed) = unixfs.file_size.filter(|claimed| *claimed != file_size) {
+                    bail!(
+                        "dag-pb block {} claims {} bytes but its content adds up to {}",
+                        cid,
+                        claimed,
+                        file_size
+                    );
+                }
+                let children = node
+                    .links
+                    .iter()
+                    .zip(unixfs.block_sizes)
+                    .map(|(link, file_size)| {
+                        Ok(DagChild {
+                            cid: from_ipld_cid(&link.cid)?,
+                            file_size,
+                        })
+                    })
+                    .collect::<Result<_>>()?;
+                Ok(DagNode::File {
+                    data: unixfs.data,
+                    children,
+                    file_size,
+                })
+            }
+            codec => bail!("Unsupported codec {:#x} for {}", codec, cid),
+        }
+    }
+
+    /// Bytes of file content in this node and everything below it.
+    pub fn file_size(&self) -> u64 {
+        match self {
+            DagNode::Leaf(data) => data.len() as u64,
+            DagNode::File { file_size, .. } => *file_size,
+        }
+    }
+}
+
+fn to_ipld_cid(cid: &Cid) -> libipld::Cid {
+    libipld::Cid::try_from(cid.to_bytes()).expect("CIDs convert between cid versions")
+}
+
+fn from_ipld_cid(cid: &libipld::Cid) -> Result<Cid> {
+    Cid::try_from(cid.to_bytes()).map_err(|e| anyhow!("Invalid link CID: {:?}", e))
+}
+
+/// The subset of the UnixFS `Data` protobuf message used for files.
+struct UnixFsData {
+    data: Vec<u8>,
+    file_size: Option<u64>,
+    block_sizes: Vec<u64>,
+}
+
+impl UnixFsData {
+    fn encode(&self) -> Vec<u8> {
+        let mut buf = Vec::new();
+        put_varint(&mut buf, 1 << 3);
+        put_varint(&mut buf, UNIXFS_FILE);
+        if !self.data.is_empty() {
+            put_varint(&mut buf, (2 << 3) | 2);
+            put_varint(&mut buf, self.data.len() as u64);
+            buf.extend_from_slice(&self.data);
+        }
+        if let Some(file_size) = self.file_size {
+            put_varint(&mut buf, 3 << 3);
+            put_varint(&mut buf, file_size);
+        }
+        for size in &self.block_sizes {
+            put_varint(&mut buf, 4 << 3);
+            put_varint(&mut buf, *size);
+        }
+        buf
+    }
+
+    fn decode(mut buf: &[u8]) -> Result<Self> {
+        let mut unixfs = UnixFsData {
+            data: Vec::new(),
+            file_size: None,
+            block_sizes: Vec::new(),
+        };
+        while !buf.is_empty() {
+            let key = get_varint(&mut buf)?;
+            match (key >> 3, key & 7) {
+                (1, 0) => {
+                    let kind = get_varint(&mut buf)?;
+                    // Raw nodes (0) only appear as leaves of older importers.
+                    if kind != UNIXFS_FILE && kind != 0 {
+                        bail!("Unsupported UnixFS node type {}", kind);
+                    }
+                }
+                (2, 2) => unixfs.data = get_bytes(&mut buf)?.to_vec(),
+                (3, 0) => unixfs.file_size = Some(get_varint(&mut buf)?),
+                (4, 0) => unixfs.block_sizes.push(get_varint(&mut buf)?),
+                (4, 2) => {
+                    let mut packed = get_bytes(&mut buf)?;
+                    while !packed.is_empty() {
+                        unixfs.block_sizes.push(get_varint(&mut packed)?);
+                    }
+                }
+                (_, 0) => {
+                    get_varint(&mut buf)?;
+                }
+                (_, 2) => {
+                    get_bytes(&mut buf)?;
+                }
+                (field, wire_type) => {
+                    bail!(
+                        "Unexpected UnixFS field {} with wire type {}",
+                        field,
+                        wire_type
+                    )
+                }
+            }
+        }
+        Ok(unixfs)
+    }
+}
+
+fn put_varint(buf: &mut Vec<u8>, mut value: u64) {
+    while value >= 0x80 {
+        buf.push(value as u8 | 0x80);
+        value >>= 7;
+    }
+    buf.push(value as u8);
+}
+
+fn get_varint(buf: &mut &[u8]) -> Result<u64> {
+    let mut value = 0u64;
+    for shift in (0..64).step_by(7) {
+        let (&byte, rest) = buf
+            .split_first()
+            .ok_or_else(|| anyhow!("Truncated UnixFS varint"))?;
+        *buf = rest;
+        value |= u64::from(byte & 0x7f) << shift;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+    bail!("UnixFS varint overflows 64 bits")
+}
+
+fn get_bytes<'a>(buf: &mut &'a [u8]) -> Result<&'a [u8]> {
+    let len = get_varint(buf)? as usize;
+    if buf.len() < len {
+        bail!("Truncated UnixFS field");
+    }
+    let (bytes, rest) = buf.split_at(len);
+    *buf = rest;
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use futures::FutureExt;
+    use std::collections::HashMap;
+    use std::sync::Arc;
+
+    fn children(sizes: &[u64]) -> Vec<DagChild> {
+        sizes
+            .iter()
+            .enumerate()
+            .map(|(i, size)| DagChild {
+                cid: block_cid(RAW_CODEC, &[i as u8]),
+                file_size: *size,
+            })
+            .collect()
+    }
+
+    /// Index of each child in range with the part of it to read.
+    fn ranges(inline_len: u64, sizes: &[u64], start: u64, end: u64) -> Vec<(usize, u64, u64)> {
+        let all = children(sizes);
+        children_in_range(inline_len, children(sizes), start, end)
+            .into_iter()
+            .map(|range| {
+                let index = all.iter().position(|c| c.cid == range.child.cid).unwrap();
+                (index, range.start, range.end)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn range_within_one_child() {
+        assert_eq!(ranges(0, &[10, 10, 10], 12, 18), vec![(1, 2, 8)]);
+    }
+
+    #[test]
+    fn range_crossing_children() {
+        assert_eq!(
+            ranges(0, &[10, 10, 10], 5, 25),
+            vec![(0, 5, 10), (1, 0, 10), (2, 0, 5)]
+        );
+    }
+
+    #[test]
+    fn range_on_child_boundaries() {
+        assert_eq!(ranges(0, &[10, 10, 10], 10, 20), vec![(1, 0, 10)]);
+        assert_eq!(ranges(0, &[10, 10, 10], 9, 11), vec![(0, 9, 10), (1, 0, 1)]);
+    }
+
+    #[test]
+    fn whole_node() {
+        assert_eq!(ranges(0, &[4, 6], 0, 10), vec![(0, 0, 4), (1, 0, 6)]);
+    }
+
+    #[test]
+    fn inline_data_comes_before_children() {
+        assert_eq!(ranges(5, &[10, 10], 0, 5), vec![]);
+        assert_eq!(ranges(5, &[10, 10], 3, 17), vec![(0, 0, 10), (1, 0, 2)]);
+    }
+
+    #[test]
+    fn empty_children_are_skipped() {
+        assert_eq!(ranges(0, &[10, 0, 10], 5, 15), vec![(0, 5, 10), (2, 0, 5)]);
+    }
+
+    fn import(data: &[u8], chunk_size: usize) -> (Cid, HashMap<Cid, Vec<u8>>) {
+        let mut importer = FileImporter::new(chunk_size);
+        let mut blocks: HashMap<_, _> = importer.push(data).into_iter().collect();
+        let (root, rest) = importer.finish();
+        blocks.extend(rest);
+        (root, blocks)
+    }
+
+    /// Reads bytes `start..end` below `cid` through `read_range`, the way
+    /// the node serves files and ranges.
+    fn read(blocks: &HashMap<Cid, Vec<u8>>, cid: &Cid, start: u64, end: u64) -> Vec<u8> {
+        let blocks = Arc::new(blocks.clone());
+        let root = DagNode::decode(cid, blocks[cid].clone()).unwrap();
+        let load = move |child: &DagChild| {
+            let cid = child.cid;
+            let block = blocks[&cid].clone();
+            async move {
+                assert!(block_matches(&cid, &block));
+                DagNode::decode(&cid, block)
+            }
+            .boxed()
+        };
+        futures::executor::block_on(read_range(root, start, end, 4, load).try_concat()).unwrap()
+    }
+
+    fn content(len: usize) -> Vec<u8> {
+        (0..len).map(|i| (i * 7 % 251) as u8).collect()
+    }
+
+    fn pb_block(unixfs: Option<Vec<u8>>, links: usize) -> (Cid, Vec<u8>) {
+        let node = PbNode {
+            links: (0..links)
+                .map(|i| PbLink {
+                    cid: to_ipld_cid(&block_cid(RAW_CODEC, &[i as u8])),
+                    name: Some(String::new()),
+                    size: Some(1),
+                })
+                .collect(),
+            data: unixfs.map(Into::into),
+        };
+        let block = node.into_bytes().into_vec();
+        (block_cid(DAG_PB_CODEC, &block), block)
+    }
+
+    fn file_block(file_size: Option<u64>, block_sizes: Vec<u64>, links: usize) -> (Cid, Vec<u8>) {
+        let unixfs = UnixFsData {
+            data: Vec::new(),
+            file_size,
+            block_sizes,
+        };
+        pb_block(Some(unixfs.encode()), links)
+    }
+
+    fn decode_err(cid: &Cid, block: Vec<u8>) -> String {
+        match DagNode::decode(cid, block) {
+            Ok(_) => panic!("{} decoded", cid),
+            Err(e) => e.to_string(),
+        }
+    }
+
+    #[test]
+    fn small_file_is_its_own_root() {
+        let data = content(100);
+        let (root, blocks) = import(&data, 256);
+        assert_eq!(root.codec(), RAW_CODEC);
+        assert_eq!(blocks.len(), 1);
+        assert_eq!(read(&blocks, &root, 0, 100), data);
+    }
+
+    #[test]
+    fn empty_file() {
+        let (root, blocks) = import(&[], 256);
+        assert_eq!(root, block_cid(RAW_CODEC, &[]));
+        assert_eq!(
+            DagNode::decode(&root, blocks[&root].clone())
+                .unwrap()
+                .file_size(),
+            0
+        );
+    }
+
+    #[test]
+    fn round_trip_through_link_node() {
+        let data = content(1000);
+        let (root, blocks) = import(&data, 64);
+        assert_eq!(root.codec(), DAG_PB_CODEC);
+        // 16 leaves, the last one partial, under one link node
+        assert_eq!(blocks.len(), 17);
+        let node = DagNode::decode(&root, blocks[&root].clone()).unwrap();
+        assert_eq!(node.file_size(), 1000);
+        match node {
+            DagNode::File { children, .. } => assert_eq!(children.len(), 16),
+            DagNode::Leaf(_) => panic!("root is a leaf"),
+        }
+        assert_eq!(read(&blocks, &root, 0, 1000), data);
+    }
+
+    #[test]
+    fn round_trip_through_several_levels() {
+        let data = content(MAX_LINKS_PER_NODE * 2 + 10);
+        let (root, blocks) = import(&data, 1);
+        match DagNode::decode(&root, blocks[&root].clone()).unwrap() {
+            DagNode::File { children, .. } => assert_eq!(children.len(), 3),
+            DagNode::Leaf(_) => panic!("root is a leaf"),
+        }
+        let len = data.len() as u64;
+        assert_eq!(read(&blocks, &root, 0, len), data);
+    }
+
+    #[test]
+    fn pushing_in_pieces_gives_the_same_dag() {
+        let data = content(1000);
+        let mut importer = FileImporter::new(64);
+        let mut blocks = Vec::new();
+        for piece in data.chunks(37) {
+            blocks.extend(importer.push(piece));
+        }
+        let (root, rest) = importer.finish();
+        blocks.extend(rest);
+        let (expected, expected_blocks) = import(&data, 64);
+        assert_eq!(root, expected);
+        assert_eq!(blocks.len(), expected_blocks.len());
+    }
+
+    #[test]
+    fn ranges_across_leaf_boundaries() {
+        let data = content(1000);
+        let (root, blocks) = import(&data, 64);
+        for (start, end) in [
+            (0, 1),
+            (60, 70),
+            (63, 64),
+            (64, 129),
+            (100, 900),
+            (999, 1000),
+        ] {
+            assert_eq!(
+                read(&blocks, &root, start, end),
+                &data[start as usize..end as usize],
+                "range {}..{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn ranges_across_link_nodes() {
+        let data = content(MAX_LINKS_PER_NODE * 2 + 10);
+        let (root, blocks) = import(&data, 1);
+        let edge = MAX_LINKS_PER_NODE as u64;
+        for (start, end) in [
+            (edge - 1, edge + 1),
+            (5, edge * 2 + 5),
+            (edge * 2, edge * 2 + 10),
+        ] {
+            assert_eq!(
+                read(&blocks, &root, start, end),
+                &data[start as usize..end as usize],
+                "range {}..{}",
+                start,
+                end
+            );
+        }
+    }
+
+    #[test]
+    fn range_read_checks_child_sizes() {
+        let (root, blocks) = import(&content(1000), 64);
+        let root = DagNode::decode(&root, blocks[&root].clone()).unwrap();
+        let load = |_: &DagChild| async { Ok(DagNode::Leaf(vec![0; 3])) }.boxed();
+        let err = futures::executor::block_on(read_range(root, 0, 100, 4, load).try_concat())
+            .unwrap_err();
+        assert!(err.to_string().contains("parent claims 64"));
+    }
+
+    #[test]
+    fn rejects_truncated_varint() {
+        let (cid, block) = pb_block(Some(vec![3 << 3, 0x80]), 0);
+        assert!(decode_err(&cid, block).contains("Truncated"));
+    }
+
+    #[test]
+    fn rejects_overlong_varint() {
+        let mut unixfs = vec![3 << 3];
+        unixfs.extend([0xff; 10]);
+        let (cid, block) = pb_block(Some(unixfs), 0);
+        assert!(decode_err(&cid, block).contains("overflows"));
+    }
+
+    #[test]
+    fn rejects_mismatched_block_sizes() {
+        let (cid, block) = file_block(None, vec![1, 2], 1);
+        assert!(decode_err(&cid, block).contains("mismatched block sizes"));
+    }
+
+    #[test]
+    fn rejects_wrong_filesize() {
+        let (cid, block) = file_block(Some(5), vec![3], 1);
+        assert!(decode_err(&cid, block).contains("claims 5 bytes"));
+    }
+
+    #[test]
+    fn rejects_sizes_overflowing_u64() {
+        let (cid, block) = file_block(None, vec![u64::MAX, 1], 2);
+        assert!(decode_err(&cid, block).contains("2^64"));
+    }
+
+    #[test]
+    fn rejects_missing_unixfs_data() {
+        let (cid, block) = pb_block(None, 1);
+        assert!(decode_err(&cid, block).contains("no UnixFS data"));
+    }
+
+    #[test]
+    fn rejects_unsupported_codec() {
+        let cid = block_cid(0x71, b"{}");
+        assert!(decode_err(&cid, b"{}".to_vec()).contains("Unsupported codec"));
+    }
+
+    #[test]
+    fn rejects_invalid_dag_pb() {
+        let cid = block_cid(DAG_PB_CODEC, &[0xff]);
+        assert!(decode_err(&cid, vec![0xff]).contains("Invalid dag-pb"));
+    }
+}
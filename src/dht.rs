@@ -0,0 +1,475 @@
+use libp2p::kad::store::{self, MemoryStore, MemoryStoreConfig, RecordStore};
+use libp2p::kad::{ProviderRecord, Record, RecordKey};
+use libp2p::{Multiaddr, PeerId};
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+use tracing::{info, warn};
+
+use crate::gc::unix_secs;
+use crate::net::NetError;
+
+/// Name of the sled tree holding Kademlia records, keyed by record key.
+const RECORDS_TREE: &str = "kad_records";
+
+/// Name of the sled tree holding the provider records of each key.
+const PROVIDERS_TREE: &str = "kad_providers";
+
+/// Name of the sled tree holding the routing table snapshot, keyed by peer.
+const ROUTING_TREE: &str = "kad_routing";
+
+/// Records held at most. Values are capped at 65 KiB by Kademlia, so this
+/// bounds them to about half a GiB.
+const MAX_RECORDS: usize = 8 * 1024;
+
+/// Keys with provider records held at most, counting both the content this
+/// node provides and the keys other peers announce to it. Every pin and
+/// upload adds one, so Kademlia's default of 1024 would stop the node from
+/// announcing new content after about as many files.
+const MAX_PROVIDED_KEYS: usize = 256 * 1024;
+
+/// The Kademlia record store of the node. Lookups are answered from memory
+/// and every change is written through to the node's database, so records
+/// and the providers announced by other peers survive a restart.
+pub struct SledRecordStore {
+    memory: MemoryStore,
+    records: sled::Tree,
+    providers: sled::Tree,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredRecord {
+    value: Vec<u8>,
+    publisher: Option<Vec<u8>>,
+    /// Seconds since the Unix epoch.
+    expires: Option<u64>,
+}
+
+#[derive(Serialize, Deserialize)]
+struct StoredProvider {
+    provider: Vec<u8>,
+    addresses: Vec<Vec<u8>>,
+    /// Seconds since the Unix epoch.
+    expires: Option<u64>,
+}
+
+impl SledRecordStore {
+    /// Opens the store of `local_id`, loading whatever has not expired
+    /// since it was written and dropping the rest.
+    pub fn open(db: &sled::Db, local_id: PeerId) -> Result<Self, NetError> {
+        let mut store = Self {
+            memory: MemoryStore::with_config(
+                local_id,
+                MemoryStoreConfig {
+                    max_records: MAX_RECORDS,
+                    max_provided_keys: MAX_PROVIDED_KEYS,
+                    ..Default::default()
+                },
+            ),
+            records: db.open_tree(RECORDS_TREE)?,
+            providers: db.open_tree(PROVIDERS_TREE)?,
+        };
+        let (records, providers) = store.load()?;
+        info!(
+            "Loaded {} DHT records and {} provider records",
+            records, providers
+        );
+        Ok(store)
+    }
+
+    fn load(&mut self) -> Result<(usize, usize), NetError> {
+        let now = unix_secs();
+        let mut records = 0;
+        for entry in self.records.iter() {
+            let (key, value) = entry?;
+            let record = decode::<StoredRecord>(&value)
+                .ok()
+                .filter(|stored| !expired(stored.expires, now))
+                .and_then(|stored| {
+                    Some(Record {
+                        key: RecordKey::from(key.to_vec()),
+                        value: stored.value,
+                        publisher: match stored.publisher {
+                            Some(bytes) => Some(PeerId::from_bytes(&bytes).ok()?),
+                            None => None,
+                        },
+                        expires: to_instant(stored.expires, now),
+                    })
+                });
+            match record.map(|record| self.memory.put(record)) {
+                Some(Ok(())) => records += 1,
+                Some(Err(e)) => warn!("Dropping stored DHT record: {}", e),
+                None => {
+                    self.records.remove(&key)?;
+                }
+            }
+        }
+
+        let mut providers = 0;
+        for entry in self.providers.iter() {
+            let (key, value) = entry?;
+            let record_key = RecordKey::from(key.to_vec());
+            let stored = decode::<Vec<StoredProvider>>(&value).unwrap_or_default();
+            for stored in stored {
+                if expired(stored.expires, now) {
+                    continue;
+                }
+                let Ok(provider) = PeerId::from_bytes(&stored.provider) else {
+                    continue;
+                };
+                let record = ProviderRecord {
+                    key: record_key.clone(),
+                    provider,
+                    expires: to_instant(stored.expires, now),
+                    addresses: stored
+                        .addresses
+                        .into_iter()
+                        .filter_map(|bytes| Multiaddr::try_from(bytes).ok())
+                        .collect(),
+                };
+                match self.memory.add_provider(record) {
+                    Ok(()) => providers += 1,
+                    Err(e) => warn!("Dropping stored provider record: {}", e),
+                }
+            }
+            // Rewrite what is left, without the expired providers
+            self.save_providers(&record_key)?;
+        }
+
+        Ok((records, providers))
+    }
+
+    fn save_record(&self, record: &Record) -> Result<(), NetError> {
+        let stored = StoredRecord {
+            value: record.value.clone(),
+            publisher: record.publisher.map(|peer_id| peer_id.to_bytes()),
+            expires: to_unix_secs(record.expires),
+        };
+        self.records.insert(record.key.to_vec(), encode(&stored)?)?;
+        Ok(())
+    }
+
+    /// Writes the providers of `key` currently in memory, replacing the
+    /// stored ones.
+    fn save_providers(&self, key: &RecordKey) -> Result<(), NetError> {
+        let providers = self.memory.providers(key);
+        if providers.is_empty() {
+            self.providers.remove(key.to_vec())?;
+            return Ok(());
+        }
+        let stored: Vec<StoredProvider> = providers
+            .into_iter()
+            .map(|record| StoredProvider {
+                provider: record.provider.to_bytes(),
+                addresses: record.addresses.iter().map(|addr| addr.to_vec()).collect(),
+                expires: to_unix_secs(record.expires),
+            })
+            .collect();
+        self.providers.insert(key.to_vec(), encode(&stored)?)?;
+        Ok(())
+    }
+}
+
+// Kademlia has no way to report storage failures, so writes that fail are
+// logged and the record only lives in memory until the next restart.
+impl RecordStore for SledRecordStore {
+    type RecordsIter<'a> = <MemoryStore as RecordStore>::RecordsIter<'a>;
+    type ProvidedIter<'a> = <MemoryStore as RecordStore>::ProvidedIter<'a>;
+
+    fn get(&self, k: &RecordKey) -> Option<Cow<'_, Record>> {
+        self.memory.get(k)
+    }
+
+    fn put(&mut self, r: Record) -> store::Result<()> {
+        self.memory.put(r.clone())?;
+        if let Err(e) = self.save_record(&r) {
+            warn!("Failed to persist DHT record: {}", e);
+        }
+        Ok(())
+    }
+
+    fn remove(&mut self, k: &RecordKey) {
+        self.memory.remove(k);
+        if let Err(e) = self.records.remove(k.to_vec()) {
+            warn!("Failed to remove persisted DHT record: {}", e);
+        }
+    }
+
+    fn records(&self) -> Self::RecordsIter<'_> {
+        self.memory.records()
+    }
+
+    fn add_provider(&mut self, record: ProviderRecord) -> store::Result<()> {
+        let key = record.key.clone();
+        self.memory.add_provider(record)?;
+        if let Err(e) = self.save_providers(&key) {
+            warn!("Failed to persist provider record: {}", e);
+        }
+        Ok(())
+    }
+
+    fn providers(&self, key: &RecordKey) -> Vec<ProviderRecord> {
+        self.memory.providers(key)
+    }
+
+    fn provided(&self) -> Self::ProvidedIter<'_> {
+        self.memory.provided()
+    }
+
+    fn remove_provider(&mut self, k: &RecordKey, p: &PeerId) {
+        self.memory.remove_provider(k, p);
+        if let Err(e) = self.save_providers(k) {
+            warn!("Failed to persist provider record: {}", e);
+        }
+    }
+}
+
+/// The peers of the routing table and their addresses, saved so a restarted
+/// node can rejoin the DHT without depending on its bootstrap peers.
+#[derive(Clone)]
+pub struct RoutingSnapshot {
+    tree: sled::Tree,
+}
+
+impl RoutingSnapshot {
+    pub fn open(db: &sled::Db) -> Result<Self, NetError> {
+        Ok(Self {
+            tree: db.open_tree(ROUTING_TREE)?,
+        })
+    }
+
+    /// Replaces the saved peers with `peers`.
+    pub fn save(&self, peers: Vec<(PeerId, Vec<Multiaddr>)>) -> Result<(), NetError> {
+        let mut batch = sled::Batch::default();
+        for key in self.tree.iter().keys() {
+            batch.remove(key?);
+        }
+        for (peer_id, addresses) in peers {
+            let addresses: Vec<Vec<u8>> = addresses.iter().map(|addr| addr.to_vec()).collect();
+            batch.insert(peer_id.to_bytes(), encode(&addresses)?);
+        }
+        self.tree.apply_batch(batch)?;
+        Ok(())
+    }
+
+    /// The saved peers, skipping entries that no longer decode.
+    pub fn load(&self) -> Result<Vec<(PeerId, Vec<Multiaddr>)>, NetError> {
+        let mut peers = Vec::new();
+        for entry in self.tree.iter() {
+            let (key, value) = entry?;
+            let Ok(peer_id) = PeerId::from_bytes(&key) else {
+                continue;
+            };
+            let addresses: Vec<Multiaddr> = decode::<Vec<Vec<u8>>>(&value)
+                .unwrap_or_default()
+                .into_iter()
+                .filter_map(|bytes| Multiaddr::try_from(bytes).ok())
+                .collect();
+            if !addresses.is_empty() {
+                peers.push((peer_id, addresses));
+            }
+        }
+        Ok(peers)
+    }
+}
+
+fn encode<T: Serialize>(value: &T) -> Result<Vec<u8>, NetError> {
+    rmp_serde::to_vec(value).map_err(|e| NetError::Blockstore(e.to_string()))
+}
+
+fn decode<T: DeserializeOwned>(bytes: &[u8]) -> Result<T, NetError> {
+    rmp_serde::from_slice(bytes).map_err(|e| NetError::Blockstore(e.to_string()))
+}
+
+fn expired(expires: Option<u64>, now: u64) -> bool {
+    expires.is_some_and(|at| at <= now)
+}
+
+/// Expiry times are kept on disk as seconds since the Unix epoch, since an
+/// `Instant` means nothing to another process.
+fn to_unix_secs(expires: Option<Instant>) -> Option<u64> {
+    expires.map(|at| unix_secs() + at.saturating_duration_since(Instant::now()).as_secs())
+}
+
+fn to_instant(expires: Option<u64>, now: u64) -> Option<Instant> {
+    expires.map(|at| Instant::now() + Duration::from_secs(at.saturating_sub(now)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    /// A database directory of its own for each test, removed when dropped.
+    struct TempDb(PathBuf);
+
+    impl TempDb {
+        fn new(name: &str) -> Self {
+            let path = std::env::temp_dir().join(format!(
+                "boxpeer-dht-test-{}-{}",
+                std::process::id(),
+                name
+            ));
+            let _ = std::fs::remove_dir_all(&path);
+            Self(path)
+        }
+
+        /// Opens the database without a background flusher, which would
+        /// keep it locked for a moment after it is dropped.
+        fn open(&self) -> sled::Db {
+            sled::Config::new()
+                .path(&self.0)
+                .flush_every_ms(None)
+                .open()
+                .unwrap()
+        }
+    }
+
+    impl Drop for TempDb {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_dir_all(&self.0);
+        }
+    }
+
+    fn record(n: usize, expires: Option<Instant>) -> Record {
+        Record {
+            key: RecordKey::new(&n.to_be_bytes()),
+            value: vec![n as u8; 4],
+            publisher: Some(PeerId::random()),
+            expires,
+        }
+    }
+
+    fn provider(n: usize, provider: PeerId) -> ProviderRecord {
+        ProviderRecord {
+            key: RecordKey::new(&n.to_be_bytes()),
+            provider,
+            expires: Some(Instant::now() + Duration::from_secs(3600)),
+            addresses: vec!["/ip4/127.0.0.1/udp/4001/quic-v1".parse().unwrap()],
+        }
+    }
+
+    #[test]
+    fn records_survive_reopening() {
+        let dir = TempDb::new("records");
+        let local_id = PeerId::random();
+        let kept = record(1, Some(Instant::now() + Duration::from_secs(3600)));
+        {
+            let mut store = SledRecordStore::open(&dir.open(), local_id).unwrap();
+            store.put(kept.clone()).unwrap();
+            store.put(record(2, None)).unwrap();
+            store.put(record(3, None)).unwrap();
+            store.remove(&record(3, None).key);
+        }
+        let store = SledRecordStore::open(&dir.open(), local_id).unwrap();
+        let loaded = store.get(&kept.key).unwrap();
+        assert_eq!(loaded.value, kept.value);
+        assert_eq!(loaded.publisher, kept.publisher);
+        assert!(loaded.expires.is_some());
+        assert!(store.get(&record(2, None).key).unwrap().expires.is_none());
+        assert!(store.get(&record(3, None).key).is_none());
+    }
+
+    #[test]
+    fn expired_records_are_dropped_on_open() {
+        let dir = TempDb::new("expired");
+        let local_id = PeerId::random();
+        {
+            let db = dir.open();
+            let store = SledRecordStore::open(&db, local_id).unwrap();
+            let stored = StoredRecord {
+                value: vec![1],
+                publisher: None,
+                expires: Some(unix_secs() - 1),
+            };
+            store
+                .records
+                .insert(record(1, None).key.to_vec(), encode(&stored).unwrap())
+                .unwrap();
+        }
+        let db = dir.open();
+        let store = SledRecordStore::open(&db, local_id).unwrap();
+        assert!(store.get(&record(1, None).key).is_none());
+        assert!(store.records.is_empty());
+    }
+
+    #[test]
+    fn provider_records_survive_reopening() {
+        let dir = TempDb::new("providers");
+        let local_id = PeerId::random();
+        let remote = PeerId::random();
+        {
+            let mut store = SledRecordStore::open(&dir.open(), local_id).unwrap();
+            store.add_provider(provider(1, local_id)).unwrap();
+            store.add_provider(provider(1, remote)).unwrap();
+            store.add_provider(provider(2, remote)).unwrap();
+            store.remove_provider(&provider(2, remote).key, &remote);
+        }
+        let store = SledRecordStore::open(&dir.open(), local_id).unwrap();
+        let mut providers: Vec<PeerId> = store
+            .providers(&provider(1, local_id).key)
+            .into_iter()
+            .map(|record| record.provider)
+            .collect();
+        providers.sort();
+        let mut expected = vec![local_id, remote];
+        expected.sort();
+        assert_eq!(providers, expected);
+        assert_eq!(
+            store.providers(&provider(1, remote).key)[0].addresses,
+            provider(1, remote).addresses
+        );
+        assert!(store.providers(&provider(2, remote).key).is_empty());
+        assert_eq!(store.provided().count(), 1);
+    }
+
+    #[test]
+    fn record_limit_is_enforced() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let mut store = SledRecordStore::open(&db, PeerId::random()).unwrap();
+        for n in 0..MAX_RECORDS {
+            store.put(record(n, None)).unwrap();
+        }
+        assert!(matches!(
+            store.put(record(MAX_RECORDS, None)),
+            Err(store::Error::MaxRecords)
+        ));
+        assert_eq!(store.records.len(), MAX_RECORDS);
+    }
+
+    #[test]
+    fn provided_key_limit_is_enforced() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let local_id = PeerId::random();
+        let mut store = SledRecordStore::open(&db, local_id).unwrap();
+        for n in 0..MAX_PROVIDED_KEYS {
+            let record = ProviderRecord::new(n.to_be_bytes().to_vec(), local_id, Vec::new());
+            store.add_provider(record).unwrap();
+        }
+        assert!(matches!(
+            store.add_provider(provider(MAX_PROVIDED_KEYS, local_id)),
+            Err(store::Error::MaxProvidedKeys)
+        ));
+        assert_eq!(store.provided().count(), MAX_PROVIDED_KEYS);
+    }
+
+    #[test]
+    fn routing_snapshot_round_trip() {
+        let db = sled::Config::new().temporary(true).open().unwrap();
+        let snapshot = RoutingSnapshot::open(&db).unwrap();
+        let first = PeerId::random();
+        let second = PeerId::random();
+        let addr: Multiaddr = "/ip4/10.0.0.1/udp/4001/quic-v1".parse().unwrap();
+        snapshot
+            .save(vec![(first, vec![addr.clone()]), (second, Vec::new())])
+            .unwrap();
+        assert_eq!(snapshot.load().unwrap(), vec![(first, vec![addr.clone()])]);
+
+        snapshot.save(vec![(second, vec![addr.clone()])]).unwrap();
+        assert_eq!(
+            RoutingSnapshot::open(&db).unwrap().load().unwrap(),
+            vec![(second, vec![addr])]
+        );
+    }
+}
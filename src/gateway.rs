@@ -0,0 +1,231 @@
+use actix_web::http::header::{
+    self, ByteRangeSpec, CacheControl, CacheDirective, ContentRange, ContentRangeSpec, ETag,
+    EntityTag, IfNoneMatch, Range,
+};
+use actix_web::web::Bytes;
+use actix_web::{error, web, HttpMessage, HttpRequest, HttpResponse, HttpResponseBuilder};
+use cid::Cid;
+use futures::stream::BoxStream;
+use futures::{StreamExt, TryStreamExt};
+use serde::{Deserialize, Serialize};
+use std::time::Duration;
+use tracing::warn;
+
+use crate::metrics::Transport;
+use crate::net::{CachePolicy, NetError, RangeResult};
+use crate::protocol::ProviderInfo;
+use crate::upload;
+use crate::AppState;
+
+/// Content addressed data never changes, so let caches keep it for a year.
+const IMMUTABLE_MAX_AGE: u32 = 365 * 24 * 60 * 60;
+
+/// Bytes answered to a range open at the end, like the `bytes=0-` browsers
+/// send for media, so players get the file a window at a time instead of
+/// the node reading all of it at once.
+const OPEN_RANGE_WINDOW: u64 = 8 * 1024 * 1024;
+
+/// How long `/providers` collects results before answering with what it found.
+const PROVIDER_LOOKUP_TIMEOUT: Duration = Duration::from_secs(30);
+
+#[derive(Deserialize)]
+pub(crate) struct GatewayQuery {
+    /// Check cached blocks against their CID and refetch mismatches.
+    #[serde(default)]
+    revalidate: bool,
+}
+
+#[derive(Serialize)]
+struct ProvidersResponse {
+    cid: String,
+    count: usize,
+    providers: Vec<ProviderInfo>,
+}
+
+// HTTP gateway route handler: GET /ipfs/{cid}
+pub(crate) async fn get_ipfs(
+    req: HttpRequest,
+    path: web::Path<String>,
+    query: web::Query<GatewayQuery>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let cid = match Cid::try_from(path.as_str()) {
+        Ok(cid) => cid,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid CID: {}", e)),
+    };
+    let policy = if query.revalidate {
+        CachePolicy::Revalidate
+    } else {
+        CachePolicy::LocalFirst
+    };
+
+    let etag = EntityTag::new_strong(cid.to_string());
+    if let Some(IfNoneMatch::Items(tags)) = req.get_header::<IfNoneMatch>() {
+        if tags.iter().any(|tag| tag.weak_eq(&etag)) {
+            return with_content_headers(HttpResponse::NotModified(), etag).finish();
+        }
+    }
+
+    // Only single ranges are served partially; anything else gets the whole file.
+    if let Some(Range::Bytes(specs)) = req.get_header::<Range>() {
+        if let [spec] = specs.as_slice() {
+            let range = state
+                .client
+                .request_file_range(cid, policy, |total_size| {
+                    let (first, last) = spec.to_satisfiable_range(total_size)?;
+                    Some(match spec {
+                        ByteRangeSpec::From(_) => (first, last.min(first + OPEN_RANGE_WINDOW - 1)),
+                        _ => (first, last),
+                    })
+                })
+                .await;
+            return match range {
+                Ok(RangeResult::Partial(range)) => {
+                    let mut builder = with_content_headers(HttpResponse::PartialContent(), etag);
+                    builder.insert_header(ContentRange(ContentRangeSpec::Bytes {
+                        range: Some((range.start, range.start + range.len - 1)),
+                        instance_length: Some(range.total_size),
+                    }));
+                    let body = FileBody {
+                        start: range.start,
+                        len: range.len,
+                        parts: range.parts,
+                    };
+                    stream_body(&state, cid, policy, builder, body).await
+                }
+                Ok(RangeResult::Unsatisfiable { total_size }) => {
+                    HttpResponse::RangeNotSatisfiable()
+                        .insert_header(ContentRange(ContentRangeSpec::Bytes {
+                            range: None,
+                            instance_length: Some(total_size),
+                        }))
+                        .finish()
+                }
+                Err(e) => fetch_error_response(e),
+            };
+        }
+    }
+
+    match state.client.stream_file(cid, policy).await {
+        Ok(file) => {
+            let body = FileBody {
+                start: 0,
+                len: file.total_size,
+                parts: file.parts,
+            };
+            let builder = with_content_headers(HttpResponse::Ok(), etag);
+            stream_body(&state, cid, policy, builder, body).await
+        }
+        Err(e) => fetch_error_response(e),
+    }
+}
+
+// Provider lookup route handler: GET /providers/{cid}
+pub(crate) async fn get_providers(
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    let cid = match Cid::try_from(path.as_str()) {
+        Ok(cid) => cid,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid CID: {}", e)),
+    };
+
+    let mut found = match state.client.find_providers(cid).await {
+        Ok(found) => found,
+        Err(e) => {
+            return HttpResponse::BadGateway().body(format!("Error looking up providers: {}", e))
+        }
+    };
+
+    let mut providers = Vec::new();
+    let _ = tokio::time::timeout(PROVIDER_LOOKUP_TIMEOUT, async {
+        while let Some(provider) = found.next().await {
+            providers.push(ProviderInfo::from(provider));
+        }
+    })
+    .await;
+
+    HttpResponse::Ok().json(ProvidersResponse {
+        cid: cid.to_string(),
+        count: providers.len(),
+        providers,
+    })
+}
+
+/// Content of a response body, read block by block.
+struct FileBody {
+    /// Where the content starts in the file.
+    start: u64,
+    len: u64,
+    parts: BoxStream<'static, anyhow::Result<Vec<u8>>>,
+}
+
+/// Answers with `body` streamed as its blocks arrive. The first block is
+/// read before answering, so a file that cannot be fetched still gets an
+/// error status, and gives the content type when the body starts the file.
+async fn stream_body(
+    state: &web::Data<AppState>,
+    cid: Cid,
+    policy: CachePolicy,
+    mut builder: HttpResponseBuilder,
+    mut body: FileBody,
+) -> HttpResponse {
+    let first = match body.parts.try_next().await {
+        Ok(first) => first,
+        Err(e) => return fetch_error_response(e),
+    };
+    let content_type = if body.start == 0 {
+        first.as_deref().and_then(upload::sniff_content_type)
+    } else {
+        // Only the start of the file tells its format
+        match state
+            .client
+            .read_head(cid, policy, upload::SNIFF_LEN as u64)
+            .await
+        {
+            Ok(head) => upload::sniff_content_type(&head),
+            Err(e) => return fetch_error_response(e),
+        }
+    };
+    builder.insert_header((
+        header::CONTENT_TYPE,
+        content_type.unwrap_or(upload::OCTET_STREAM),
+    ));
+
+    // A block failing part way can only cut the response short
+    let metrics = state.metrics.clone();
+    let parts =
+        futures::stream::iter(first.map(Ok))
+            .chain(body.parts)
+            .map(move |part| match part {
+                Ok(part) => {
+                    metrics.record_bytes_served(Transport::Http, part.len());
+                    Ok(Bytes::from(part))
+                }
+                Err(e) => {
+                    warn!("Aborting response for {}: {}", cid, e);
+                    Err(error::ErrorBadGateway(e))
+                }
+            });
+    builder.no_chunking(body.len).streaming(parts)
+}
+
+fn with_content_headers(mut builder: HttpResponseBuilder, etag: EntityTag) -> HttpResponseBuilder {
+    builder
+        .insert_header((header::ACCEPT_RANGES, "bytes"))
+        .insert_header(ETag(etag))
+        .insert_header(CacheControl(vec![
+            CacheDirective::Public,
+            CacheDirective::MaxAge(IMMUTABLE_MAX_AGE),
+            CacheDirective::Extension("immutable".to_string(), None),
+        ]));
+    builder
+}
+
+fn fetch_error_response(e: anyhow::Error) -> HttpResponse {
+    match e.downcast_ref::<NetError>() {
+        Some(NetError::TimedOut { .. }) => HttpResponse::GatewayTimeout().body(e.to_string()),
+        Some(NetError::EventLoopStopped) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+        _ => HttpResponse::BadGateway().body(format!("Error fetching file: {}", e)),
+    }
+}
@@ -0,0 +1,471 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use cid::Cid;
+use serde::Serialize;
+use std::collections::hash_map::Entry;
+use std::collections::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+use tracing::info;
+
+use crate::net::NetError;
+use crate::upload;
+use crate::AppState;
+
+/// Name of the sled tree holding the last access time and size of each
+/// block.
+const ACCESS_TREE: &str = "block_access";
+
+/// Tree in which `SledBlockstore` (blockstore 0.7) keeps its blocks, keyed
+/// by CID. Read once to log the blocks stored before the access log existed.
+const BLOCKS_TREE: &str = "BLOCKSTORE.BLOCKS";
+
+/// Key in the default tree set once the blockstore has been scanned into the
+/// access log.
+const SCANNED_KEY: &[u8] = b"block_access_scanned";
+
+/// Access times of blocks read from memory or disk, buffered before they are
+/// written out together so reads do not each cost a write.
+const TOUCH_BATCH: usize = 256;
+
+/// Blocks used more recently than this are never evicted: reads may still
+/// need them, and peers that just sent a block do not answer a new want
+/// for it until the next full wantlist exchange.
+pub const GC_GRACE_PERIOD: Duration = Duration::from_secs(60);
+
+/// Every stored block with when it was last read or stored and its size,
+/// so garbage collection can evict the least recently used ones first
+/// without reading the blocks themselves. Entries are written whenever a
+/// block is stored; blocks stored before this log existed are added by a
+/// one-time scan of the blockstore when it is first opened.
+#[derive(Clone)]
+pub struct AccessLog {
+    access: sled::Tree,
+    /// Access times not yet written to `access`, by CID.
+    pending: Arc<Mutex<HashMap<Cid, u64>>>,
+}
+
+impl AccessLog {
+    pub fn open(db: &sled::Db) -> Result<Self, NetError> {
+        let log = Self {
+            access: db.open_tree(ACCESS_TREE)?,
+            pending: Default::default(),
+        };
+        if !db.contains_key(SCANNED_KEY)? {
+            log.scan(&db.open_tree(BLOCKS_TREE)?)?;
+            db.insert(SCANNED_KEY, &[])?;
+        }
+        Ok(log)
+    }
+
+    /// Logs every block in `blocks` that has no entry yet, as the least
+    /// recently used, so blocks stored before the log existed count toward
+    /// the quota and are evicted first.
+    fn scan(&self, blocks: &sled::Tree) -> Result<(), NetError> {
+        let mut added = 0;
+        for block in blocks.iter() {
+            let (key, value) = block?;
+            let entry = AccessEntry {
+                last_access: 0,
+                size: value.len() as u64,
+            };
+            let swapped =
+                self.access
+                    .compare_and_swap(key, None as Option<&[u8]>, Some(entry.encode()))?;
+            if swapped.is_ok() {
+                added += 1;
+            }
+        }
+        if added > 0 {
+            info!("Added {} existing blocks to the access log", added);
+        }
+        Ok(())
+    }
+
+    /// Records that a block of `size` bytes was just stored.
+    pub fn record(&self, cid: &Cid, size: u64) -> Result<(), NetError> {
+        self.pending_touches().remove(cid);
+        let entry = AccessEntry {
+            last_access: unix_secs(),
+            size,
+        };
+        self.access.insert(cid.to_bytes(), entry.encode())?;
+        Ok(())
+    }
+
+    /// Records that a stored block was just read. The time is written out
+    /// with the next batch.
+    pub fn touch(&self, cid: &Cid) -> Result<(), NetError> {
+        let full = {
+            let mut pending = self.pending_touches();
+            pending.insert(*cid, unix_secs());
+            pending.len() >= TOUCH_BATCH
+        };
+        if full {
+            self.flush()?;
+        }
+        Ok(())
+    }
+
+    /// Writes out the buffered access times. Blocks removed in the meantime
+    /// are skipped rather than added back.
+    pub fn flush(&self) -> Result<(), NetError> {
+        let touched = std::mem::take(&mut *self.pending_touches());
+        for (cid, last_access) in touched {
+            self.access.update_and_fetch(cid.to_bytes(), |entry| {
+                let mut entry = AccessEntry::decode(entry?);
+                entry.last_access = entry.last_access.max(last_access);
+                Some(entry.encode())
+            })?;
+        }
+        Ok(())
+    }
+
+    pub fn remove(&self, cid: &Cid) -> Result<(), NetError> {
+        self.pending_touches().remove(cid);
+        self.access.remove(cid.to_bytes())?;
+        Ok(())
+    }
+
+    /// CIDs of every stored block.
+    pub fn stored_cids(&self) -> Result<Vec<Cid>, NetError> {
+        self.access
+            .iter()
+            .keys()
+            .map(|key| decode_cid(&key?))
+            .collect()
+    }
+
+    /// Every stored block with its size and last access time in seconds
+    /// since the Unix epoch.
+    pub fn blocks(&self) -> Result<Vec<StoredBlock>, NetError> {
+        self.flush()?;
+        self.access
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let entry = AccessEntry::decode(&value);
+                Ok(StoredBlock {
+                    cid: decode_cid(&key)?,
+                    size: entry.size,
+                    last_access: entry.last_access,
+                })
+            })
+            .collect()
+    }
+
+    fn pending_touches(&self) -> MutexGuard<'_, HashMap<Cid, u64>> {
+        self.pending.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+/// An access log value: the last access time followed by the block size,
+/// both big-endian.
+struct AccessEntry {
+    last_access: u64,
+    size: u64,
+}
+
+impl AccessEntry {
+    fn encode(&self) -> Vec<u8> {
+        let mut bytes = self.last_access.to_be_bytes().to_vec();
+        bytes.extend_from_slice(&self.size.to_be_bytes());
+        bytes
+    }
+
+    fn decode(bytes: &[u8]) -> Self {
+        let field = |at: usize| {
+            bytes
+                .get(at..at + 8)
+                .and_then(|field| field.try_into().ok())
+                .map(u64::from_be_bytes)
+                .unwrap_or_default()
+        };
+        Self {
+            last_access: field(0),
+            size: field(8),
+        }
+    }
+}
+
+fn decode_cid(key: &[u8]) -> Result<Cid, NetError> {
+    Cid::try_from(key).map_err(|e| NetError::Blockstore(format!("Invalid stored CID: {}", e)))
+}
+
+/// Blocks stored by uploads and pins that have not been pinned yet, which
+/// garbage collection leaves alone. Counted, since two uploads of the same
+/// content share blocks.
+#[derive(Clone, Default)]
+pub struct PendingBlocks {
+    blocks: Arc<Mutex<HashMap<Cid, usize>>>,
+}
+
+impl PendingBlocks {
+    /// Starts protecting the blocks of one upload or pin, until the returned
+    /// hold is dropped.
+    pub fn hold(&self) -> PendingHold {
+        PendingHold {
+            pending: self.clone(),
+            cids: Default::default(),
+        }
+    }
+
+    pub fn contains(&self, cid: &Cid) -> bool {
+        self.lock().contains_key(cid)
+    }
+
+    fn lock(&self) -> MutexGuard<'_, HashMap<Cid, usize>> {
+        self.blocks.lock().unwrap_or_else(|e| e.into_inner())
+    }
+}
+
+pub struct PendingHold {
+    pending: PendingBlocks,
+    cids: Mutex<Vec<Cid>>,
+}
+
+impl PendingHold {
+    /// Protects `cid`. Called under the garbage collection lock, right
+    /// after the block is stored.
+    pub fn add(&self, cid: Cid) {
+        *self.pending.lock().entry(cid).or_default() += 1;
+        self.cids
+            .lock()
+            .unwrap_or_else(|e| e.into_inner())
+            .push(cid);
+    }
+}
+
+impl Drop for PendingHold {
+    fn drop(&mut self) {
+        let mut blocks = self.pending.lock();
+        let cids = self.cids.get_mut().unwrap_or_else(|e| e.into_inner());
+        for cid in cids.drain(..) {
+            if let Entry::Occupied(mut held) = blocks.entry(cid) {
+                *held.get_mut() -= 1;
+                if *held.get() == 0 {
+                    held.remove();
+                }
+            }
+        }
+    }
+}
+
+/// Seconds since the Unix epoch, the unit of access times.
+pub fn unix_secs() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+pub struct StoredBlock {
+    pub cid: Cid,
+    pub size: u64,
+    pub last_access: u64,
+}
+
+/// Picks the blocks to remove so that the `stored_bytes` of `blocks` fit
+/// `target`: least recently used first, leaving alone the blocks `keep`
+/// protects and those used within `GC_GRACE_PERIOD` of `now`.
+pub fn select_evictions(
+    mut blocks: Vec<StoredBlock>,
+    stored_bytes: u64,
+    target: u64,
+    now: u64,
+    keep: impl Fn(&Cid) -> bool,
+) -> Vec<StoredBlock> {
+    let recent = now.saturating_sub(GC_GRACE_PERIOD.as_secs());
+    blocks.retain(|block| !keep(&block.cid) && block.last_access < recent);
+    blocks.sort_by_key(|block| block.last_access);
+    let mut remaining = stored_bytes;
+    blocks
+        .into_iter()
+        .take_while(|block| {
+            let over = remaining > target;
+            remaining = remaining.saturating_sub(block.size);
+            over
+        })
+        .collect()
+}
+
+/// Outcome of a garbage collection pass.
+#[derive(Serialize, Clone, Copy, Debug, Default)]
+pub struct GcReport {
+    pub removed_blocks: usize,
+    pub freed_bytes: u64,
+    /// Bytes of blocks left in the blockstore.
+    pub stored_bytes: u64,
+}
+
+// Garbage collection route handler: POST /gc, runs a pass right away
+pub(crate) async fn post_gc(req: HttpRequest, state: web::Data<AppState>) -> HttpResponse {
+    if !upload::is_authorized(&state, upload::bearer_token(&req)) {
+        return HttpResponse::Unauthorized().body("A valid upload token is required");
+    }
+    match state.client.collect_garbage().await {
+        Ok(report) => HttpResponse::Ok().json(report),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Garbage collection failed: {}", e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::{block_cid, RAW_CODEC};
+    use std::collections::HashSet;
+
+    fn cid(n: u8) -> Cid {
+        block_cid(RAW_CODEC, &[n])
+    }
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn block(n: u8, size: u64, last_access: u64) -> StoredBlock {
+        StoredBlock {
+            cid: cid(n),
+            size,
+            last_access,
+        }
+    }
+
+    fn entry(last_access: u64, size: u64) -> Vec<u8> {
+        AccessEntry { last_access, size }.encode()
+    }
+
+    fn logged(log: &AccessLog) -> HashMap<Cid, (u64, u64)> {
+        log.blocks()
+            .unwrap()
+            .into_iter()
+            .map(|block| (block.cid, (block.size, block.last_access)))
+            .collect()
+    }
+
+    fn evicted(blocks: Vec<StoredBlock>, target: u64, keep: impl Fn(&Cid) -> bool) -> Vec<Cid> {
+        let stored = blocks.iter().map(|block| block.size).sum();
+        select_evictions(blocks, stored, target, 1_000, keep)
+            .into_iter()
+            .map(|block| block.cid)
+            .collect()
+    }
+
+    #[test]
+    fn records_size_and_access_time() {
+        let log = AccessLog::open(&temp_db()).unwrap();
+        let before = unix_secs();
+        log.record(&cid(1), 30).unwrap();
+        let (size, last_access) = logged(&log)[&cid(1)];
+        assert_eq!(size, 30);
+        assert!(last_access >= before);
+        assert_eq!(log.stored_cids().unwrap(), vec![cid(1)]);
+    }
+
+    #[test]
+    fn touches_are_written_on_flush() {
+        let db = temp_db();
+        let log = AccessLog::open(&db).unwrap();
+        log.access.insert(cid(1).to_bytes(), entry(5, 30)).unwrap();
+        log.touch(&cid(1)).unwrap();
+        let on_disk = AccessEntry::decode(&log.access.get(cid(1).to_bytes()).unwrap().unwrap());
+        assert_eq!(on_disk.last_access, 5);
+        log.flush().unwrap();
+        assert!(logged(&log)[&cid(1)].1 > 5);
+        assert_eq!(logged(&log)[&cid(1)].0, 30);
+    }
+
+    #[test]
+    fn touch_does_not_add_back_removed_blocks() {
+        let log = AccessLog::open(&temp_db()).unwrap();
+        log.record(&cid(1), 30).unwrap();
+        log.touch(&cid(1)).unwrap();
+        log.remove(&cid(1)).unwrap();
+        log.record(&cid(2), 30).unwrap();
+        log.remove(&cid(2)).unwrap();
+        log.touch(&cid(2)).unwrap();
+        assert!(logged(&log).is_empty());
+    }
+
+    #[test]
+    fn open_logs_existing_blocks_once() {
+        let db = temp_db();
+        let blocks = db.open_tree(BLOCKS_TREE).unwrap();
+        blocks.insert(cid(1).to_bytes(), vec![0; 40]).unwrap();
+        blocks.insert(cid(2).to_bytes(), vec![0; 10]).unwrap();
+        let log = AccessLog::open(&db).unwrap();
+        assert_eq!(logged(&log)[&cid(1)], (40, 0));
+        assert_eq!(logged(&log)[&cid(2)], (10, 0));
+
+        log.remove(&cid(2)).unwrap();
+        let log = AccessLog::open(&db).unwrap();
+        assert_eq!(logged(&log).len(), 1);
+    }
+
+    #[test]
+    fn scan_keeps_existing_entries() {
+        let db = temp_db();
+        db.open_tree(BLOCKS_TREE)
+            .unwrap()
+            .insert(cid(1).to_bytes(), vec![0; 40])
+            .unwrap();
+        db.open_tree(ACCESS_TREE)
+            .unwrap()
+            .insert(cid(1).to_bytes(), entry(7, 40))
+            .unwrap();
+        let log = AccessLog::open(&db).unwrap();
+        assert_eq!(logged(&log)[&cid(1)], (40, 7));
+    }
+
+    #[test]
+    fn pending_hold_protects_until_dropped() {
+        let pending = PendingBlocks::default();
+        let first = pending.hold();
+        let second = pending.hold();
+        first.add(cid(1));
+        second.add(cid(1));
+        second.add(cid(2));
+        drop(second);
+        assert!(pending.contains(&cid(1)));
+        assert!(!pending.contains(&cid(2)));
+        drop(first);
+        assert!(!pending.contains(&cid(1)));
+    }
+
+    #[test]
+    fn evicts_least_recently_used_first() {
+        let blocks = vec![block(1, 10, 300), block(2, 10, 100), block(3, 10, 200)];
+        assert_eq!(evicted(blocks, 15, |_| false), vec![cid(2), cid(3)]);
+    }
+
+    #[test]
+    fn stops_once_under_target() {
+        let blocks = vec![block(1, 10, 100), block(2, 10, 200)];
+        assert_eq!(evicted(blocks, 20, |_| false), Vec::<Cid>::new());
+        let blocks = vec![block(1, 10, 100), block(2, 10, 200)];
+        assert_eq!(evicted(blocks, 0, |_| false), vec![cid(1), cid(2)]);
+    }
+
+    #[test]
+    fn keeps_recently_used_blocks() {
+        let recent = 1_000 - GC_GRACE_PERIOD.as_secs();
+        let blocks = vec![block(1, 10, recent), block(2, 10, recent - 1)];
+        assert_eq!(evicted(blocks, 0, |_| false), vec![cid(2)]);
+    }
+
+    #[test]
+    fn pinned_and_pending_blocks_survive() {
+        let pinned = HashSet::from([cid(1)]);
+        let pending = PendingBlocks::default();
+        let hold = pending.hold();
+        hold.add(cid(2));
+        let keep = |cid: &Cid| pinned.contains(cid) || pending.contains(cid);
+        let blocks = vec![block(1, 10, 100), block(2, 10, 100), block(3, 10, 200)];
+        assert_eq!(evicted(blocks, 0, keep), vec![cid(3)]);
+
+        drop(hold);
+        let blocks = vec![block(1, 10, 100), block(2, 10, 100), block(3, 10, 200)];
+        assert_eq!(evicted(blocks, 0, keep), vec![cid(2), cid(3)]);
+    }
+}
@@ -1,24 +1,47 @@
+mod cache;
+mod config;
+mod dag;
+mod dht;
+mod gateway;
+mod gc;
+mod metrics;
 mod net;
 mod node;
+mod pin;
+mod protocol;
+mod status;
+mod upload;
+use crate::config::{Cli, CliCommand, Config, IdentityAction};
+use crate::metrics::{Metrics, Transport};
+use crate::net::{CachePolicy, NetError, P2PCDNClient};
+use crate::protocol::{
+    Announcement, ClientCommand, ErrorCode, FrameWriter, RequestId, ServerMessage, ServerResponse,
+};
+use crate::upload::UploadResponse;
 use actix::prelude::*;
-use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer, Error};
+use actix_web::web::Bytes;
+use actix_web::{web, App, Error, HttpRequest, HttpResponse, HttpServer};
 use actix_web_actors::ws;
+use anyhow::Context as _;
 use cid::Cid;
-use libp2p::Multiaddr;
+use clap::Parser;
+use futures::{FutureExt, StreamExt, TryStreamExt};
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicUsize, Ordering};
 use std::sync::Arc;
-use tokio::sync::Mutex;
 use std::time::{Duration, Instant};
-use crate::net::P2PCDNClient;
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::{broadcast, watch, Notify};
+use tracing::{debug, info, warn};
 
-
-struct BinaryMessage {
-    cid: Cid,
-    data: Vec<u8>,
-}
-struct TextMessage(String);
+struct BinaryFrame(Vec<u8>);
+struct TextMessage(ServerResponse);
+struct CloseIfDrained;
+struct UploadEnded;
+type UploadSender = tokio::sync::mpsc::Sender<Result<Bytes, anyhow::Error>>;
 
 // Implement `actix::Message` for these custom message types
-impl Message for BinaryMessage {
+impl Message for BinaryFrame {
     type Result = ();
 }
 
@@ -26,22 +49,93 @@ impl Message for TextMessage {
     type Result = ();
 }
 
+impl Message for CloseIfDrained {
+    type Result = ();
+}
+
+impl Message for UploadEnded {
+    type Result = ();
+}
+
 // Shared state across WebSocket connections
 struct AppState {
-    client: Arc<Mutex<P2PCDNClient>>,
+    client: P2PCDNClient,
+    max_concurrent_fetches: usize, // Upper bound on CIDs fetched in parallel per request
+    upload_token: Option<String>,  // Uploads are disabled when unset
+    shutdown: watch::Receiver<bool>, // Set once the server starts shutting down
+    metrics: Arc<Metrics>,
+}
+
+// Bytes of binary frames queued on a socket that the connection has not
+// taken yet; file transfers wait while it is full
+#[derive(Clone, Default)]
+struct SendWindow {
+    queued: Arc<AtomicUsize>,
+    released: Arc<Notify>,
+}
+
+impl SendWindow {
+    /// Queues a frame on the socket once fewer than `SEND_WINDOW` bytes are
+    /// waiting to be sent.
+    async fn send(&self, addr: &Addr<P2PWebSocket>, frame: Vec<u8>) {
+        loop {
+            // Registered before the check so a release in between is not missed
+            let released = self.released.notified();
+            if self.queued.load(Ordering::Acquire) < SEND_WINDOW {
+                break;
+            }
+            released.await;
+        }
+        self.queued.fetch_add(frame.len(), Ordering::AcqRel);
+        addr.do_send(BinaryFrame(frame));
+    }
+
+    /// Called with what the connection took from the socket's output. Text
+    /// replies and frame headers are counted too, which only errs towards
+    /// sending a little early.
+    fn release(&self, bytes: usize) {
+        let _ = self
+            .queued
+            .fetch_update(Ordering::AcqRel, Ordering::Acquire, |queued| {
+                Some(queued.saturating_sub(bytes))
+            });
+        self.released.notify_waiters();
+    }
 }
 
 // WebSocket Actor
 pub struct P2PWebSocket {
     state: web::Data<AppState>, // Shared state to access the P2PCDNClient
+    window: SendWindow,         // File frames queued but not yet sent
     hb: Instant,                // Heartbeat to track connection health
+    in_flight: HashMap<RequestId, SpawnHandle>, // Running requests that can be cancelled by id
+    upload: Option<UploadSender>, // Upload currently receiving binary frames
+    next_transfer: u32,         // Id tagging the frames of the next file sent
+    running: usize,             // Requests still running, with or without an id
+    closing: bool,              // Server shutdown: finish running requests, then close
 }
 
 impl P2PWebSocket {
-    pub fn new(state: web::Data<AppState>) -> Self {
+    fn new(state: web::Data<AppState>, window: SendWindow) -> Self {
         Self {
             state,
+            window,
             hb: Instant::now(),
+            in_flight: HashMap::new(),
+            upload: None,
+            next_transfer: 0,
+            running: 0,
+            closing: false,
+        }
+    }
+
+    /// Once the server is shutting down and the last request has finished,
+    /// closes the socket telling the client the server is going away. The
+    /// check goes through the mailbox so replies the request queued are
+    /// delivered first.
+    fn close_if_drained(&mut self, ctx: &mut ws::WebsocketContext<Self>) {
+        if self.closing && self.running == 0 {
+            ctx.address().do_send(CloseIfDrained);
         }
     }
 
@@ -49,7 +143,7 @@ impl P2PWebSocket {
     fn hb(&self, ctx: &mut <Self as Actor>::Context) {
         ctx.run_interval(HEARTBEAT_INTERVAL, |act, ctx| {
             if Instant::now().duration_since(act.hb) > CLIENT_TIMEOUT {
-                println!("Client heartbeat failed, disconnecting!");
+                info!("Client heartbeat failed, disconnecting");
                 ctx.stop();
                 return;
             }
@@ -57,49 +151,517 @@ impl P2PWebSocket {
         });
     }
     fn handle_text_message(&mut self, ctx: &mut ws::WebsocketContext<Self>, text: String) {
-        if text.starts_with("GET_FILES:") {
-            let cid_strs = text.trim_start_matches("GET_FILES:");
-            let cids: Vec<Cid> = cid_strs
-                .split(',')
-                .filter_map(|s| Cid::try_from(s.trim()).ok())
-                .collect();
-
-            if cids.is_empty() {
-                ctx.text("No valid CIDs provided");
+        let request = match protocol::parse_request(&text) {
+            Ok(request) => request,
+            Err(response) => {
+                ctx.text(response.to_json());
                 return;
             }
+        };
 
-            let state = self.state.clone();
-            let addr = ctx.address(); // Cloneable address for async communication
-            ctx.spawn(
-                async move {
-                    let mut client = state.client.lock().await;
-                    // println!("Fetching file for CID: {:?}", &cids);
-                    for cid_ in cids {
-                        println!("Fetching file for CID: {:?}", &cid_);
-                        match client.request_file(cid_).await {
-                            Ok(file_data) => {
-                                println!("Sending data for CID: {:?}", &cid_);
-                                addr.do_send(BinaryMessage {
-                                    cid: cid_,
-                                    data: file_data,
-                                });
-                            }
-                            Err(e) => {
-                                let error_message = format!("Error fetching file for CID {}: {}", cid_, e);
-                                addr.do_send(TextMessage(error_message));
-                            }
-                        }
+        // Requests that start new work are refused while shutting down or
+        // without a network; cancelling and finishing uploads still help.
+        let starts_work = matches!(
+            request.command,
+            ClientCommand::GetFiles { .. }
+                | ClientCommand::FindProviders { .. }
+                | ClientCommand::Upload { .. }
+                | ClientCommand::Subscribe
+                | ClientCommand::Pin { .. }
+                | ClientCommand::Unpin { .. }
+        );
+        if starts_work && self.closing {
+            ctx.text(
+                ServerResponse::error(
+                    request.id,
+                    ErrorCode::ShuttingDown,
+                    "Server is shutting down",
+                )
+                .to_json(),
+            );
+            return;
+        }
+        if starts_work && !self.state.client.is_running() {
+            ctx.text(
+                ServerResponse::error(
+                    request.id,
+                    ErrorCode::Unavailable,
+                    "The network is unavailable",
+                )
+                .to_json(),
+            );
+            return;
+        }
+
+        // Replies and `CANCEL` go by id, so a second request under the id of
+        // a running one could not be told apart from it
+        if let Some(id) = request
+            .id
+            .as_ref()
+            .filter(|id| starts_work && self.in_flight.contains_key(*id))
+        {
+            ctx.text(
+                ServerResponse::error(
+                    request.id.clone(),
+                    ErrorCode::DuplicateRequest,
+                    format!("A request with id {:?} is already in flight", id),
+                )
+                .to_json(),
+            );
+            return;
+        }
+
+        match request.command {
+            ClientCommand::GetFiles { cids, revalidate } => {
+                self.get_files(ctx, request.id, cids, revalidate)
+            }
+            ClientCommand::FindProviders { cid } => self.find_providers(ctx, request.id, cid),
+            ClientCommand::Cancel { target } => self.cancel(ctx, request.id, target),
+            ClientCommand::Upload { token } => self.start_upload(ctx, request.id, token),
+            ClientCommand::UploadFinish => self.finish_upload(ctx, request.id),
+            ClientCommand::Subscribe => self.subscribe(ctx, request.id),
+            ClientCommand::Pin { cid, token } => self.pin(ctx, request.id, cid, token, true),
+            ClientCommand::Unpin { cid, token } => self.pin(ctx, request.id, cid, token, false),
+            ClientCommand::ListPins => self.list_pins(ctx, request.id),
+        }
+    }
+
+    /// Handles `PIN` and `UNPIN`, which change what the node keeps and so
+    /// need the upload token.
+    fn pin(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        cid_str: String,
+        token: Option<String>,
+        pin: bool,
+    ) {
+        if !upload::is_authorized(&self.state, token.as_deref()) {
+            ctx.text(
+                ServerResponse::error(
+                    id,
+                    ErrorCode::Unauthorized,
+                    "A valid upload token is required",
+                )
+                .to_json(),
+            );
+            return;
+        }
+        let cid = match Cid::try_from(cid_str.trim()) {
+            Ok(cid) => cid,
+            Err(_) => {
+                ctx.text(
+                    ServerResponse::cid_error(id, ErrorCode::InvalidCid, cid_str, "Invalid CID")
+                        .to_json(),
+                );
+                return;
+            }
+        };
+
+        let client = self.state.client.clone();
+        let addr = ctx.address();
+        let reply_id = id.clone();
+        self.spawn_request(ctx, id, async move {
+            let response = if pin {
+                match client.pin(cid).await {
+                    Ok(pinned) => ServerResponse::new(
+                        reply_id,
+                        ServerMessage::Pinned {
+                            cid: cid.to_string(),
+                            announcement: Announcement::from(&pinned.announced),
+                        },
+                    ),
+                    Err(e) => ServerResponse::cid_error(
+                        reply_id,
+                        ErrorCode::PinFailed,
+                        cid,
+                        format!("Error pinning file: {}", e),
+                    ),
+                }
+            } else {
+                match client.unpin(cid).await {
+                    Ok(true) => ServerResponse::new(
+                        reply_id,
+                        ServerMessage::Unpinned {
+                            cid: cid.to_string(),
+                        },
+                    ),
+                    Ok(false) => {
+                        ServerResponse::cid_error(reply_id, ErrorCode::NotPinned, cid, "Not pinned")
                     }
+                    Err(e) => ServerResponse::cid_error(
+                        reply_id,
+                        ErrorCode::PinFailed,
+                        cid,
+                        format!("Error unpinning file: {}", e),
+                    ),
                 }
-                .into_actor(self)
-                .then(|_result, _act, _ctx| fut::ready(())),
+            };
+            addr.do_send(TextMessage(response));
+        });
+    }
+
+    fn list_pins(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<RequestId>) {
+        let response = match self.state.client.list_pins() {
+            Ok(pins) => ServerResponse::new(
+                id,
+                ServerMessage::Pins {
+                    pins: pins.into_iter().map(Into::into).collect(),
+                },
+            ),
+            Err(e) => ServerResponse::error(
+                id,
+                ErrorCode::PinFailed,
+                format!("Error listing pins: {}", e),
+            ),
+        };
+        ctx.text(response.to_json());
+    }
+
+    fn start_upload(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        token: Option<String>,
+    ) {
+        if !upload::is_authorized(&self.state, token.as_deref()) {
+            ctx.text(
+                ServerResponse::error(
+                    id,
+                    ErrorCode::Unauthorized,
+                    "A valid upload token is required",
+                )
+                .to_json(),
             );
-        } else {
-            ctx.text("Unknown command");
+            return;
+        }
+        if self.upload.is_some() {
+            ctx.text(
+                ServerResponse::error(
+                    id,
+                    ErrorCode::UploadInProgress,
+                    "Another upload is still open",
+                )
+                .to_json(),
+            );
+            return;
+        }
+
+        // Binary frames are forwarded into the client's importer as they
+        // arrive; closing the channel on UPLOAD_FINISH completes the file.
+        let (sender, receiver) =
+            tokio::sync::mpsc::channel::<Result<Bytes, anyhow::Error>>(UPLOAD_BUFFER);
+        let receiver = tokio_stream::wrappers::ReceiverStream::new(receiver);
+        let client = self.state.client.clone();
+        let addr = ctx.address();
+        let reply_id = id.clone();
+        // The import runs on its own task, since the actor stops handling
+        // events while it waits for room in the channel. Dropping the handle
+        // with the socket still cancels it.
+        let (upload, handle) = async move {
+            let mut head = Vec::new();
+            let result = client
+                .upload_stream(receiver.inspect(|chunk| {
+                    if let Ok(chunk) = chunk {
+                        upload::capture_head(&mut head, chunk);
+                    }
+                }))
+                .await;
+            let response = match result {
+                Ok(uploaded) => ServerResponse::new(
+                    reply_id,
+                    ServerMessage::Uploaded(UploadResponse::new(uploaded, &head, None)),
+                ),
+                Err(e) => {
+                    let code = match e.downcast_ref::<NetError>() {
+                        Some(NetError::UploadTooLarge { .. }) => ErrorCode::UploadTooLarge,
+                        _ => ErrorCode::UploadFailed,
+                    };
+                    ServerResponse::error(reply_id, code, format!("Upload failed: {}", e))
+                }
+            };
+            addr.do_send(TextMessage(response));
+            addr.do_send(UploadEnded);
+        }
+        .remote_handle();
+        actix::spawn(upload);
+        // Tracked without an id: uploads are not cancellable, but shutdown
+        // waits for them.
+        self.spawn_request(ctx, None, handle);
+
+        self.upload = Some(sender);
+        ctx.text(ServerResponse::new(id, ServerMessage::UploadReady).to_json());
+    }
+
+    fn finish_upload(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<RequestId>) {
+        // Dropping the sender ends the stream; the reply carries the id of
+        // the UPLOAD request.
+        if self.upload.take().is_none() {
+            ctx.text(ServerResponse::error(id, ErrorCode::NoUpload, "No upload is open").to_json());
         }
     }
 
+    fn handle_binary_message(&mut self, ctx: &mut ws::WebsocketContext<Self>, bin: Bytes) {
+        match &self.upload {
+            // A failed upload has already reported its error, so frames sent
+            // after that are dropped.
+            Some(upload) => match upload.try_send(Ok(bin)) {
+                Ok(()) | Err(TrySendError::Closed(_)) => {}
+                // Stop reading the socket until the importer catches up
+                Err(TrySendError::Full(frame)) => {
+                    let upload = upload.clone();
+                    ctx.wait(
+                        async move {
+                            let _ = upload.send(frame).await;
+                        }
+                        .into_actor(self),
+                    );
+                }
+            },
+            None => ctx.text(
+                ServerResponse::error(
+                    None,
+                    ErrorCode::NoUpload,
+                    "Binary data without an open upload",
+                )
+                .to_json(),
+            ),
+        }
+    }
+
+    fn cancel(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        target: RequestId,
+    ) {
+        // Dropping the request's future drops its pending fetches, which the
+        // network event loop then cancels.
+        match self.in_flight.remove(&target) {
+            Some(handle) => {
+                // A cancelled future never reaches the bookkeeping in
+                // `spawn_request`, so it is done here.
+                ctx.cancel_future(handle);
+                self.running -= 1;
+                ctx.text(ServerResponse::new(Some(target), ServerMessage::Cancelled).to_json());
+                self.close_if_drained(ctx);
+            }
+            None => ctx.text(
+                ServerResponse::error(
+                    id,
+                    ErrorCode::UnknownRequest,
+                    format!("No request in flight with id {:?}", target),
+                )
+                .to_json(),
+            ),
+        }
+    }
+
+    fn get_files(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        cid_strs: Vec<String>,
+        revalidate: bool,
+    ) {
+        let (cids, invalid) = protocol::parse_cids(&cid_strs);
+        for cid_str in &invalid {
+            ctx.text(
+                ServerResponse::cid_error(
+                    id.clone(),
+                    ErrorCode::InvalidCid,
+                    cid_str,
+                    "Invalid CID",
+                )
+                .to_json(),
+            );
+        }
+
+        if cids.is_empty() {
+            ctx.text(
+                ServerResponse::error(id, ErrorCode::NoValidCids, "No valid CIDs provided")
+                    .to_json(),
+            );
+            return;
+        }
+
+        // Each file gets its own transfer id, so its frames stay apart from
+        // those of other requests for the same CID
+        let transfers: Vec<(Cid, u32)> = cids
+            .into_iter()
+            .map(|cid| {
+                let transfer = self.next_transfer;
+                self.next_transfer = self.next_transfer.wrapping_add(1);
+                (cid, transfer)
+            })
+            .collect();
+        let state = self.state.clone();
+        let window = self.window.clone();
+        let limit = self.state.max_concurrent_fetches;
+        let policy = if revalidate {
+            CachePolicy::Revalidate
+        } else {
+            CachePolicy::LocalFirst
+        };
+        let addr = ctx.address(); // Cloneable address for async communication
+        let reply_id = id.clone();
+        self.spawn_request(ctx, id, async move {
+            let file_id = reply_id.clone();
+            let mut results = futures::stream::iter(transfers)
+                .map(|(cid_, transfer)| {
+                    let file = send_file(
+                        state.clone(),
+                        addr.clone(),
+                        window.clone(),
+                        file_id.clone(),
+                        cid_,
+                        transfer,
+                        policy,
+                    );
+                    async move {
+                        debug!("Fetching file for CID: {}", cid_);
+                        (cid_, transfer, file.await)
+                    }
+                })
+                .buffer_unordered(limit);
+
+            let (mut fetched, mut failed) = (0, 0);
+            while let Some((cid_, transfer, result)) = results.next().await {
+                match result {
+                    Ok(()) => {
+                        debug!("Sent file for CID: {}", cid_);
+                        fetched += 1;
+                    }
+                    Err(e) => {
+                        failed += 1;
+                        let code = match e.downcast_ref::<NetError>() {
+                            Some(NetError::TimedOut { .. }) => ErrorCode::Timeout,
+                            Some(NetError::EventLoopStopped) => ErrorCode::Unavailable,
+                            _ => ErrorCode::FetchFailed,
+                        };
+                        addr.do_send(TextMessage(ServerResponse::new(
+                            reply_id.clone(),
+                            ServerMessage::Error {
+                                code,
+                                message: format!("Error fetching file: {}", e),
+                                cid: Some(cid_.to_string()),
+                                transfer: Some(transfer),
+                            },
+                        )));
+                    }
+                }
+            }
+            addr.do_send(TextMessage(ServerResponse::new(
+                reply_id,
+                ServerMessage::Done { fetched, failed },
+            )));
+        });
+    }
+
+    fn find_providers(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        cid_str: String,
+    ) {
+        let cid = match Cid::try_from(cid_str.trim()) {
+            Ok(cid) => cid,
+            Err(_) => {
+                ctx.text(
+                    ServerResponse::cid_error(id, ErrorCode::InvalidCid, cid_str, "Invalid CID")
+                        .to_json(),
+                );
+                return;
+            }
+        };
+
+        let client = self.state.client.clone();
+        let addr = ctx.address();
+        let reply_id = id.clone();
+        self.spawn_request(ctx, id, async move {
+            let mut providers = match client.find_providers(cid).await {
+                Ok(providers) => providers,
+                Err(e) => {
+                    addr.do_send(TextMessage(ServerResponse::cid_error(
+                        reply_id,
+                        ErrorCode::LookupFailed,
+                        cid,
+                        format!("Error looking up providers: {}", e),
+                    )));
+                    return;
+                }
+            };
+
+            let mut count = 0;
+            while let Some(provider) = providers.next().await {
+                count += 1;
+                addr.do_send(TextMessage(ServerResponse::new(
+                    reply_id.clone(),
+                    ServerMessage::Provider {
+                        cid: cid.to_string(),
+                        provider: provider.into(),
+                    },
+                )));
+            }
+            addr.do_send(TextMessage(ServerResponse::new(
+                reply_id,
+                ServerMessage::ProvidersDone {
+                    cid: cid.to_string(),
+                    count,
+                },
+            )));
+        });
+    }
+
+    fn subscribe(&mut self, ctx: &mut ws::WebsocketContext<Self>, id: Option<RequestId>) {
+        let mut events = self.state.client.subscribe_events();
+        let mut shutdown = self.state.shutdown.clone();
+        let addr = ctx.address();
+        let reply_id = id.clone();
+        // A subscription only ends by CANCEL, so it also stops when the
+        // server shuts down to let the socket drain.
+        self.spawn_request(ctx, id, async move {
+            loop {
+                let message = tokio::select! {
+                    event = events.recv() => match event {
+                        Ok(event) => ServerMessage::Event { event: event.into() },
+                        Err(broadcast::error::RecvError::Lagged(count)) => {
+                            ServerMessage::EventsMissed { count }
+                        }
+                        Err(broadcast::error::RecvError::Closed) => break,
+                    },
+                    _ = shutdown.wait_for(|closing| *closing) => break,
+                };
+                addr.do_send(TextMessage(ServerResponse::new(reply_id.clone(), message)));
+            }
+        });
+    }
+
+    /// Runs the work of a request on the actor, remembering it under the
+    /// request id so `CANCEL` can abort it. Ids of requests in flight are
+    /// refused before they get here, so no handle is overwritten.
+    fn spawn_request<F>(
+        &mut self,
+        ctx: &mut ws::WebsocketContext<Self>,
+        id: Option<RequestId>,
+        request: F,
+    ) where
+        F: std::future::Future<Output = ()> + 'static,
+    {
+        let finished_id = id.clone();
+        self.running += 1;
+        let handle = ctx.spawn(request.into_actor(self).then(|_result, act, ctx| {
+            if let Some(finished_id) = finished_id {
+                act.in_flight.remove(&finished_id);
+            }
+            act.running -= 1;
+            act.close_if_drained(ctx);
+            fut::ready(())
+        }));
+        if let Some(id) = id {
+            self.in_flight.insert(id, handle);
+        }
+    }
 }
 
 // Implement Actor trait for WebSocket
@@ -107,22 +669,50 @@ impl Actor for P2PWebSocket {
     type Context = ws::WebsocketContext<Self>;
 
     fn started(&mut self, ctx: &mut Self::Context) {
+        self.state.metrics.websocket_opened();
         self.hb(ctx);
+
+        let mut shutdown = self.state.shutdown.clone();
+        ctx.spawn(
+            async move {
+                let _ = shutdown.wait_for(|closing| *closing).await;
+            }
+            .into_actor(self)
+            .map(|_, act, ctx| {
+                act.closing = true;
+                act.close_if_drained(ctx);
+            }),
+        );
+    }
+
+    fn stopped(&mut self, _ctx: &mut Self::Context) {
+        self.state.metrics.websocket_closed();
     }
 }
 
-// Implement the `actix::Handler` for the `BinaryMessage`
-impl Handler<BinaryMessage> for P2PWebSocket {
+// Implement the `actix::Handler` for the `BinaryFrame`
+impl Handler<BinaryFrame> for P2PWebSocket {
     type Result = ();
 
-    fn handle(&mut self, msg: BinaryMessage, ctx: &mut Self::Context) {
-        let binary_data_base64 = base64::encode(msg.data);
-        let message = format!(
-            r#"{{ "cid": "{}", "data": "{}" }}"#,
-            msg.cid.to_string(),
-            binary_data_base64
-        );
-        ctx.text(message);
+    fn handle(&mut self, msg: BinaryFrame, ctx: &mut Self::Context) {
+        ctx.binary(msg.0);
+    }
+}
+
+// Drops the sender of an upload whose task has ended, so a failed upload
+// does not keep blocking new ones. A later upload is still running and
+// keeps its sender.
+impl Handler<UploadEnded> for P2PWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, _msg: UploadEnded, _ctx: &mut Self::Context) {
+        if self
+            .upload
+            .as_ref()
+            .is_some_and(|upload| upload.is_closed())
+        {
+            self.upload = None;
+        }
     }
 }
 
@@ -131,7 +721,21 @@ impl Handler<TextMessage> for P2PWebSocket {
     type Result = ();
 
     fn handle(&mut self, msg: TextMessage, ctx: &mut Self::Context) {
-        ctx.text(msg.0);
+        ctx.text(msg.0.to_json());
+    }
+}
+
+impl Handler<CloseIfDrained> for P2PWebSocket {
+    type Result = ();
+
+    fn handle(&mut self, _msg: CloseIfDrained, ctx: &mut Self::Context) {
+        if self.closing && self.running == 0 {
+            ctx.close(Some(ws::CloseReason {
+                code: ws::CloseCode::Away,
+                description: Some("Server shutting down".to_string()),
+            }));
+            ctx.stop();
+        }
     }
 }
 
@@ -150,7 +754,7 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for P2PWebSocket {
                 self.handle_text_message(ctx, text.to_string());
             }
             Ok(ws::Message::Binary(bin)) => {
-                ctx.binary(bin);
+                self.handle_binary_message(ctx, bin);
             }
             Ok(ws::Message::Close(reason)) => {
                 ctx.close(reason);
@@ -162,39 +766,204 @@ impl StreamHandler<Result<ws::Message, ws::ProtocolError>> for P2PWebSocket {
 }
 
 // WebSocket route handler
-async fn ws_handler(req: HttpRequest, stream: web::Payload, state: web::Data<AppState>) -> Result<HttpResponse, Error> {
-    let ws = P2PWebSocket::new(state.clone());
-    ws::start(ws, &req, stream)
+async fn ws_handler(
+    req: HttpRequest,
+    stream: web::Payload,
+    state: web::Data<AppState>,
+) -> Result<HttpResponse, Error> {
+    if !state.client.is_running() {
+        return Ok(HttpResponse::ServiceUnavailable().body("The network is unavailable"));
+    }
+    let window = SendWindow::default();
+    let ws = P2PWebSocket::new(state.clone(), window.clone());
+    // The HTTP layer only pulls more of the socket's output once the
+    // connection has room, so what it pulls has left the send window
+    let output = ws::WebsocketContext::create(ws, stream).inspect(move |chunk| {
+        if let Ok(bytes) = chunk {
+            window.release(bytes.len());
+        }
+    });
+    Ok(ws::handshake(&req)?.streaming(output))
+}
+
+/// Streams one file to a socket: a `FILE` reply, then the content as chunk
+/// frames tagged with `transfer`. Further blocks are only read while the
+/// connection keeps taking the frames, so neither end holds more than a
+/// window of the file.
+async fn send_file(
+    state: web::Data<AppState>,
+    addr: Addr<P2PWebSocket>,
+    window: SendWindow,
+    id: Option<RequestId>,
+    cid: Cid,
+    transfer: u32,
+    policy: CachePolicy,
+) -> anyhow::Result<()> {
+    let mut file = state.client.stream_file(cid, policy).await?;
+    addr.do_send(TextMessage(ServerResponse::new(
+        id,
+        ServerMessage::File {
+            cid: cid.to_string(),
+            size: file.total_size,
+            chunks: protocol::chunk_count(file.total_size),
+            transfer,
+        },
+    )));
+
+    let mut frames = FrameWriter::new(transfer, cid, file.total_size);
+    while let Some(part) = file.parts.try_next().await? {
+        state
+            .metrics
+            .record_bytes_served(Transport::WebSocket, part.len());
+        for frame in frames.push(&part) {
+            window.send(&addr, frame).await;
+        }
+    }
+    if let Some(frame) = frames.finish() {
+        window.send(&addr, frame).await;
+    }
+    Ok(())
+}
+
+// Import or export the node identity: `identity export <file>` / `identity import <file>`
+fn identity_command(config: &Config, action: IdentityAction) -> anyhow::Result<()> {
+    let data_dir = &config.network.data_dir;
+    let (peer_id, done) = match action {
+        IdentityAction::Export { file } => (node::export_keypair(data_dir, &file)?, "exported"),
+        IdentityAction::Import { file } => (node::import_keypair(data_dir, &file)?, "imported"),
+    };
+    println!("Identity {} {}", peer_id, done);
+    Ok(())
 }
 
 // Start the HTTP server and WebSocket handler
 #[actix_web::main]
-async fn main() -> std::io::Result<()> {
-    let bootstrap_peers: Option<Vec<Multiaddr>> = Some(vec![
-        "/ip4/203.161.57.50/udp/9090/quic-v1".parse().unwrap(),
-    ]);
-    let (client, _network_events, network_event_loop) = P2PCDNClient::new(bootstrap_peers, None).await.unwrap();
+async fn main() -> anyhow::Result<()> {
+    let mut cli = Cli::parse();
+    let command = cli.command.take();
+    let config = Config::load(cli)?;
+
+    if let Some(CliCommand::Identity { action }) = command {
+        return identity_command(&config, action);
+    }
+
+    tracing_subscriber::fmt()
+        .with_env_filter(config.log_filter)
+        .init();
 
-    // Spawn the network event loop
-    tokio::spawn(network_event_loop.run());
+    let metrics = Arc::new(Metrics::new());
+    let (client, network_event_loop) = P2PCDNClient::new(&config.network, None, metrics.clone())
+        .await
+        .map_err(|e| anyhow::anyhow!("{}", e))
+        .context("Failed to start the network node")?;
+    let client = client
+        .with_fetch_timeout(config.fetch_timeout)
+        .with_chunk_size(config.chunk_size)
+        .with_cache_size(config.cache_size)
+        .with_storage_quota(config.storage_quota)
+        .with_max_upload_size(config.max_upload_size);
 
+    // Spawn the network event loop under its supervisor
+    let network = network_event_loop.spawn();
+
+    // Without a quota nothing is evicted on its own, only by POST /gc
+    let gc = config
+        .storage_quota
+        .map(|_| client.spawn_gc(config.gc_interval));
+    let reprovider = config
+        .reprovide
+        .map(|reprovide| client.spawn_reprovider(reprovide));
+
+    let (shutdown_sender, shutdown) = watch::channel(false);
     let app_state = web::Data::new(AppState {
-        client: Arc::new(Mutex::new(client)),
+        client: client.clone(),
+        max_concurrent_fetches: config.max_concurrent_fetches,
+        upload_token: config.upload_token,
+        shutdown,
+        metrics,
     });
 
-    HttpServer::new(move || {
+    let server = HttpServer::new(move || {
         App::new()
             .app_data(app_state.clone())
             .route("/ws", web::get().to(ws_handler)) // WebSocket route
+            .route("/ipfs/{cid}", web::get().to(gateway::get_ipfs)) // HTTP gateway route
+            .route("/providers/{cid}", web::get().to(gateway::get_providers)) // Provider lookup route
+            .route("/upload", web::post().to(upload::post_upload)) // Upload route
+            .route("/pins", web::get().to(pin::get_pins)) // Pin list route
+            .route("/pins/{cid}", web::post().to(pin::post_pin)) // Pin route
+            .route("/pins/{cid}", web::delete().to(pin::delete_pin)) // Unpin route
+            .route("/gc", web::post().to(gc::post_gc)) // Garbage collection route
+            .route("/healthz", web::get().to(status::get_healthz)) // Liveness probe
+            .route("/readyz", web::get().to(status::get_readyz)) // Readiness probe
+            .route("/status", web::get().to(status::get_status)) // Node status
+            .route("/metrics", web::get().to(metrics::get_metrics)) // Prometheus metrics
     })
     .client_request_timeout(Duration::from_secs(0))
     .client_disconnect_timeout(Duration::from_secs(0))
-    .bind("127.0.0.1:9090")?
-    .workers(2)
-    .run()
-    .await
+    .bind(config.http_bind)
+    .with_context(|| format!("Failed to bind HTTP server to {}", config.http_bind))?
+    .workers(config.workers)
+    .shutdown_timeout(config.shutdown_timeout.as_secs())
+    .disable_signals()
+    .run();
+
+    // Stop accepting connections on SIGINT/SIGTERM and give running requests
+    // the shutdown timeout to finish; sockets close once theirs are done.
+    let server_handle = server.handle();
+    let shutdown_timeout = config.shutdown_timeout;
+    tokio::spawn(async move {
+        shutdown_signal().await;
+        info!(
+            "Shutting down, waiting up to {:?} for in-flight requests",
+            shutdown_timeout
+        );
+        let _ = shutdown_sender.send(true);
+        server_handle.stop(true).await;
+    });
+    server.await?;
+
+    // Only then stop the network, which flushes the blockstore
+    for task in gc.into_iter().chain(reprovider) {
+        task.abort();
+    }
+    if let Err(e) = client.shutdown().await {
+        warn!("Network did not shut down cleanly: {}", e);
+    }
+    network.await?;
+    info!("Shutdown complete");
+    Ok(())
+}
+
+// Resolves on SIGINT (Ctrl-C) or SIGTERM
+async fn shutdown_signal() {
+    #[cfg(unix)]
+    {
+        use tokio::signal::unix::{signal, SignalKind};
+        match signal(SignalKind::terminate()) {
+            Ok(mut terminate) => {
+                tokio::select! {
+                    _ = tokio::signal::ctrl_c() => {}
+                    _ = terminate.recv() => {}
+                }
+                return;
+            }
+            Err(e) => warn!("Cannot listen for SIGTERM: {}", e),
+        }
+    }
+    if let Err(e) = tokio::signal::ctrl_c().await {
+        warn!("Cannot listen for Ctrl-C: {}", e);
+        std::future::pending::<()>().await;
+    }
 }
 
+// Upload frames buffered for the importer before the socket stops being read
+const UPLOAD_BUFFER: usize = 16;
+
+// Bytes of file frames queued on a socket before transfers wait for the
+// connection to take them
+const SEND_WINDOW: usize = 1024 * 1024;
+
 // Heartbeat constants
 const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(10);
 const CLIENT_TIMEOUT: Duration = Duration::from_secs(30);
@@ -0,0 +1,213 @@
+use actix_web::http::header;
+use actix_web::{web, HttpResponse};
+use libp2p::metrics::{Metrics as Libp2pMetrics, Recorder};
+use prometheus_client::encoding::text::encode;
+use prometheus_client::metrics::counter::Counter;
+use prometheus_client::metrics::family::Family;
+use prometheus_client::metrics::gauge::Gauge;
+use prometheus_client::metrics::histogram::{exponential_buckets, Histogram};
+use prometheus_client::registry::Registry;
+use std::time::Duration;
+
+use crate::AppState;
+
+const OPENMETRICS_CONTENT_TYPE: &str = "application/openmetrics-text; version=1.0.0; charset=utf-8";
+
+type Labels = [(&'static str, &'static str); 1];
+type HistogramFamily = Family<Labels, Histogram, fn() -> Histogram>;
+
+/// How a single block fetch from the network ended.
+#[derive(Clone, Copy)]
+pub enum FetchOutcome {
+    Ok,
+    Timeout,
+    Error,
+}
+
+/// Where content is served to clients.
+#[derive(Clone, Copy)]
+pub enum Transport {
+    Http,
+    WebSocket,
+}
+
+/// Prometheus metrics of the node, including the swarm and protocol metrics
+/// of libp2p under the `libp2p_` prefix.
+pub struct Metrics {
+    registry: Registry,
+    libp2p: Libp2pMetrics,
+    block_fetch_duration: HistogramFamily,
+    file_requests: Family<Labels, Counter>,
+    cache_lookups: Family<Labels, Counter>,
+    bytes_served: Family<Labels, Counter>,
+    websocket_sessions: Gauge,
+    connected_peers: Gauge,
+    blockstore_bytes: Gauge,
+    cache_bytes: Gauge,
+    gc_freed_bytes: Counter,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        let mut registry = Registry::default();
+        let libp2p = Libp2pMetrics::new(&mut registry);
+
+        let boxpeer = registry.sub_registry_with_prefix("boxpeer");
+        let block_fetch_duration: HistogramFamily =
+            Family::new_with_constructor(|| Histogram::new(exponential_buckets(0.01, 2.0, 14)));
+        boxpeer.register(
+            "block_fetch_duration_seconds",
+            "Time taken to fetch a block from the network, by outcome",
+            block_fetch_duration.clone(),
+        );
+        let file_requests = Family::default();
+        boxpeer.register(
+            "file_requests",
+            "File requests by whether the root block was found locally or fetched",
+            file_requests.clone(),
+        );
+        let cache_lookups = Family::default();
+        boxpeer.register(
+            "block_cache_lookups",
+            "Block reads by whether the memory cache held the block",
+            cache_lookups.clone(),
+        );
+        let bytes_served = Family::default();
+        boxpeer.register(
+            "served_bytes",
+            "File content sent to clients, by transport",
+            bytes_served.clone(),
+        );
+        let websocket_sessions = Gauge::default();
+        boxpeer.register(
+            "websocket_sessions",
+            "Open WebSocket sessions",
+            websocket_sessions.clone(),
+        );
+        let connected_peers = Gauge::default();
+        boxpeer.register(
+            "connected_peers",
+            "Peers with at least one open connection",
+            connected_peers.clone(),
+        );
+        let blockstore_bytes = Gauge::default();
+        boxpeer.register(
+            "blockstore_bytes",
+            "Size of the blockstore on disk",
+            blockstore_bytes.clone(),
+        );
+        let cache_bytes = Gauge::default();
+        boxpeer.register(
+            "block_cache_bytes",
+            "Size of the blocks held in the memory cache",
+            cache_bytes.clone(),
+        );
+        let gc_freed_bytes = Counter::default();
+        boxpeer.register(
+            "gc_freed_bytes",
+            "Bytes of blocks removed by garbage collection",
+            gc_freed_bytes.clone(),
+        );
+
+        Self {
+            registry,
+            libp2p,
+            block_fetch_duration,
+            file_requests,
+            cache_lookups,
+            bytes_served,
+            websocket_sessions,
+            connected_peers,
+            blockstore_bytes,
+            cache_bytes,
+            gc_freed_bytes,
+        }
+    }
+
+    /// Records a swarm or protocol event with the libp2p metrics.
+    pub fn record<E>(&self, event: &E)
+    where
+        Libp2pMetrics: Recorder<E>,
+    {
+        self.libp2p.record(event);
+    }
+
+    pub fn record_block_fetch(&self, outcome: FetchOutcome, elapsed: Duration) {
+        let outcome = match outcome {
+            FetchOutcome::Ok => "ok",
+            FetchOutcome::Timeout => "timeout",
+            FetchOutcome::Error => "error",
+        };
+        self.block_fetch_duration
+            .get_or_create(&[("outcome", outcome)])
+            .observe(elapsed.as_secs_f64());
+    }
+
+    pub fn record_file_request(&self, local: bool) {
+        let source = if local { "local" } else { "network" };
+        self.file_requests
+            .get_or_create(&[("source", source)])
+            .inc();
+    }
+
+    pub fn record_cache_lookup(&self, hit: bool) {
+        let result = if hit { "hit" } else { "miss" };
+        self.cache_lookups
+            .get_or_create(&[("result", result)])
+            .inc();
+    }
+
+    pub fn record_bytes_served(&self, transport: Transport, bytes: usize) {
+        let transport = match transport {
+            Transport::Http => "http",
+            Transport::WebSocket => "websocket",
+        };
+        self.bytes_served
+            .get_or_create(&[("transport", transport)])
+            .inc_by(bytes as u64);
+    }
+
+    pub fn websocket_opened(&self) {
+        self.websocket_sessions.inc();
+    }
+
+    pub fn websocket_closed(&self) {
+        self.websocket_sessions.dec();
+    }
+
+    pub fn set_connected_peers(&self, peers: usize) {
+        self.connected_peers.set(peers as i64);
+    }
+
+    pub fn set_blockstore_bytes(&self, bytes: u64) {
+        self.blockstore_bytes.set(bytes as i64);
+    }
+
+    pub fn set_cache_bytes(&self, bytes: usize) {
+        self.cache_bytes.set(bytes as i64);
+    }
+
+    pub fn record_gc(&self, freed_bytes: u64) {
+        self.gc_freed_bytes.inc_by(freed_bytes);
+    }
+
+    fn encode(&self) -> String {
+        let mut body = String::new();
+        encode(&mut body, &self.registry).expect("Writing to a String never fails");
+        body
+    }
+}
+
+// Metrics route handler: GET /metrics in the OpenMetrics text format
+pub(crate) async fn get_metrics(state: web::Data<AppState>) -> HttpResponse {
+    // Storage sizes are only sampled when scraped
+    if let Ok(status) = state.client.status().await {
+        state.metrics.set_blockstore_bytes(status.blockstore_bytes);
+    }
+    state
+        .metrics
+        .set_cache_bytes(state.client.cache_stats().bytes);
+    HttpResponse::Ok()
+        .insert_header((header::CONTENT_TYPE, OPENMETRICS_CONTENT_TYPE))
+        .body(state.metrics.encode())
+}
@@ -1,13 +1,20 @@
-use crate::node::boxpeer_dir;
+use crate::cache::{BlockCache, CacheStats, DEFAULT_CACHE_SIZE};
+use crate::dag::{
+    block_matches, read_range, DagNode, FileImporter, DAG_PB_CODEC, DEFAULT_CHUNK_SIZE,
+};
+use crate::dht::{RoutingSnapshot, SledRecordStore};
+use crate::gc::{select_evictions, unix_secs, AccessLog, GcReport, PendingBlocks, PendingHold};
+use crate::metrics::{FetchOutcome, Metrics};
 use crate::node::load_or_generate_keypair;
+use crate::pin::{Pin, PinStore};
 use anyhow::{anyhow, Result};
 use beetswap;
-use blockstore::block::CidError;
-use blockstore::{block::Block, Blockstore, SledBlockstore};
+use blockstore::{Blockstore, SledBlockstore};
 use cid::Cid;
 use futures::channel::{mpsc, oneshot};
-use futures::{SinkExt, Stream, StreamExt};
-use libp2p::kad::store::MemoryStore;
+use futures::future::BoxFuture;
+use futures::stream::BoxStream;
+use futures::{FutureExt, Stream, StreamExt, TryStreamExt};
 use libp2p::multiaddr::Protocol;
 use libp2p::{
     identify, identity, kad, mdns,
@@ -16,77 +23,342 @@ use libp2p::{
 };
 use libp2p::{PeerId, StreamProtocol};
 use libp2p_kad::RecordKey;
-use multihash_codetable::{Code, MultihashDigest};
 use sled;
 use std::collections::{HashMap, HashSet};
 use std::error::Error;
+use std::fmt;
+use std::future::Future;
 use std::path::PathBuf;
+use std::str::FromStr;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
-use std::time::Duration;
-use std::{fs};
+use std::time::{Duration, Instant};
+use tokio::io::{AsyncRead, AsyncReadExt};
 use tokio::select;
-use tracing::{info, warn};
+use tokio::sync::{broadcast, RwLock};
+use tokio::task::JoinHandle;
+use tracing::{debug, error, info, warn};
 
 const BOXPEER_PROTO_NAME: StreamProtocol = StreamProtocol::new("/ipfs/0.1.0");
 
-struct FileBlock(Vec<u8>);
+/// How long fetching a block may take before giving up. Streamed reads and
+/// walks that keep a whole DAG, like pinning, apply it to each block;
+/// `request_file` applies it to the whole read.
+pub const DEFAULT_FETCH_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// Largest file accepted for upload when none is configured.
+pub const DEFAULT_MAX_UPLOAD_SIZE: u64 = 1024 * 1024 * 1024;
+
+/// Blocks of one file fetched in parallel while reassembling it.
+const DAG_FETCH_CONCURRENCY: usize = 8;
+
+/// Largest buffer reserved up front for file content.
+const MAX_PREALLOCATION: u64 = 16 * 1024 * 1024;
+
+/// Bytes read from an upload source at a time.
+const UPLOAD_READ_BUFFER_SIZE: usize = 64 * 1024;
+
+/// How often the event loop looks for requests whose caller has gone away.
+const CANCEL_SWEEP_INTERVAL: Duration = Duration::from_secs(1);
+
+/// How often the routing table is saved, besides on shutdown.
+const ROUTING_SNAPSHOT_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
+/// How often the reprovider checks whether the node has reached the network.
+const REPROVIDE_READY_POLL: Duration = Duration::from_secs(5);
+
+/// Pause between two batches of reprovider announcements.
+const REPROVIDE_BATCH_PAUSE: Duration = Duration::from_secs(1);
+
+/// Commands queued for the event loop before callers wait for room. Shared
+/// by every clone of the client.
+const COMMAND_BUFFER: usize = 64;
+
+/// Network events buffered per subscriber before the slowest ones miss some.
+const NETWORK_EVENT_BUFFER: usize = 256;
+
+/// Errors raised by the network layer and handed to callers of the client.
+#[derive(Clone, Debug)]
+pub enum NetError {
+    /// No peer delivered the block before the fetch deadline.
+    TimedOut { cid: Cid, after: Duration },
+    /// Bitswap gave up on a block.
+    FetchFailed { cid: Cid, reason: String },
+    /// The event loop has stopped and no longer answers commands.
+    EventLoopStopped,
+    /// The swarm could not listen on an address.
+    Listen { addr: Multiaddr, reason: String },
+    /// Kademlia refused to announce the node as a provider.
+    Provide { cid: Cid, reason: String },
+    /// The local blockstore failed.
+    Blockstore(String),
+    /// An upload went past the largest file the node accepts.
+    UploadTooLarge { limit: u64 },
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            NetError::TimedOut { cid, after } => {
+                write!(f, "Timed out fetching {} after {:?}", cid, after)
+            }
+            NetError::FetchFailed { cid, reason } => {
+                write!(f, "Failed to fetch {}: {}", cid, reason)
+            }
+            NetError::EventLoopStopped => write!(f, "The network event loop has stopped"),
+            NetError::Listen { addr, reason } => {
+                write!(f, "Failed to listen on {}: {}", addr, reason)
+            }
+            NetError::Provide { cid, reason } => {
+                write!(f, "Failed to start providing {}: {}", cid, reason)
+            }
+            NetError::Blockstore(reason) => write!(f, "Blockstore error: {}", reason),
+            NetError::UploadTooLarge { limit } => {
+                write!(f, "Upload is larger than the limit of {} bytes", limit)
+            }
+        }
+    }
+}
+
+impl Error for NetError {}
+
+impl<T> From<tokio::sync::mpsc::error::SendError<T>> for NetError {
+    fn from(_: tokio::sync::mpsc::error::SendError<T>) -> Self {
+        NetError::EventLoopStopped
+    }
+}
 
-impl Block<64> for FileBlock {
-    fn cid(&self) -> Result<cid::CidGeneric<64>, CidError> {
-        let hash = Code::Sha2_256.digest(self.0.as_ref());
-        Ok(Cid::new_v1(0x55, hash))
+impl From<oneshot::Canceled> for NetError {
+    fn from(_: oneshot::Canceled) -> Self {
+        NetError::EventLoopStopped
     }
+}
 
-    fn data(&self) -> &[u8] {
-        &self.0.as_ref()
+impl From<sled::Error> for NetError {
+    fn from(e: sled::Error) -> Self {
+        NetError::Blockstore(e.to_string())
     }
 }
 
+impl From<blockstore::Error> for NetError {
+    fn from(e: blockstore::Error) -> Self {
+        NetError::Blockstore(e.to_string())
+    }
+}
+
+/// A byte range of a file read block by block, as returned by
+/// `request_file_range`.
+pub struct FileRange {
+    pub total_size: u64,
+    pub start: u64,
+    /// Bytes in the range, at least one.
+    pub len: u64,
+    /// The content of the range in order, adding up to `len` bytes.
+    pub parts: BoxStream<'static, Result<Vec<u8>>>,
+}
+
+/// A whole file read block by block, as returned by `stream_file`.
+pub struct FileStream {
+    pub total_size: u64,
+    /// The content in order, adding up to `total_size` bytes.
+    pub parts: BoxStream<'static, Result<Vec<u8>>>,
+}
+
+/// A peer announcing that it provides some content.
+pub struct Provider {
+    pub peer_id: PeerId,
+    pub addresses: Vec<Multiaddr>,
+}
+
+pub struct UploadedFile {
+    pub cid: Cid,
+    pub size: u64,
+    /// Outcome of announcing the file on the DHT.
+    pub announced: Result<(), NetError>,
+}
+
+/// Content that is stored and pinned. The announcement on the DHT can fail
+/// on its own; the content is then found once the reprovider gets through.
+pub struct Pinned {
+    pub announced: Result<(), NetError>,
+}
+
+/// Settings of the libp2p node and its local storage.
+pub struct NetworkConfig {
+    /// Directory holding the blockstore and the node identity.
+    pub data_dir: PathBuf,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub bootstrap_peers: Vec<Multiaddr>,
+    /// Whether Kademlia republishes the node's provider records itself.
+    /// Turned off when the reprovider announces the content instead.
+    pub republish_providers: bool,
+}
+
+/// Something that happened on the network, as seen by `subscribe_events`.
+#[derive(Clone, Debug)]
+pub enum NetworkEvent {
+    /// The first connection to a peer was established.
+    PeerConnected {
+        peer_id: PeerId,
+    },
+    /// The last connection to a peer was closed.
+    PeerDisconnected {
+        peer_id: PeerId,
+    },
+    /// A DHT lookup found a peer providing `cid`.
+    ProviderFound {
+        cid: Cid,
+        peer_id: PeerId,
+    },
+    /// Bitswap delivered a block this node asked for.
+    BlockReceived {
+        cid: Cid,
+        size: usize,
+    },
+    /// A peer was added to or updated in the routing table.
+    RoutingUpdated {
+        peer_id: PeerId,
+        addresses: Vec<Multiaddr>,
+    },
+    ListenAddrAdded {
+        address: Multiaddr,
+    },
+    ListenAddrRemoved {
+        address: Multiaddr,
+    },
+}
+
+/// A snapshot of the node, as returned by `status`.
+pub struct NodeStatus {
+    pub peer_id: PeerId,
+    pub listen_addrs: Vec<Multiaddr>,
+    pub connected_peers: usize,
+    pub routing_table_size: usize,
+    pub blockstore_bytes: u64,
+    pub uptime: Duration,
+    /// Whether the node has reached the network at least once.
+    pub ready: bool,
+}
+
+/// How reads treat blocks already in the local blockstore.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum CachePolicy {
+    /// Serve cached and local blocks as they are, only missing blocks are
+    /// fetched.
+    #[default]
+    LocalFirst,
+    /// Bypass the memory cache, check every local block against its CID and
+    /// fetch the ones that do not match again. Meant for debugging a suspect
+    /// blockstore.
+    Revalidate,
+}
+
+/// Which content the reprovider announces again.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum ReprovideStrategy {
+    /// The roots of pinned DAGs.
+    #[default]
+    Pinned,
+    /// The roots of every stored DAG, pinned or only cached.
+    All,
+}
+
+impl FromStr for ReprovideStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> std::result::Result<Self, Self::Err> {
+        match s {
+            "pinned" => Ok(ReprovideStrategy::Pinned),
+            "all" => Ok(ReprovideStrategy::All),
+            other => Err(format!("expected `pinned` or `all`, got {:?}", other)),
+        }
+    }
+}
+
+/// How the reprovider paces its announcements.
+#[derive(Clone, Copy, Debug)]
+pub struct ReprovideConfig {
+    pub strategy: ReprovideStrategy,
+    pub interval: Duration,
+    /// Announcements in flight at once.
+    pub batch_size: usize,
+}
+
+pub enum RangeResult {
+    Partial(FileRange),
+    /// The requested range lies outside a file of `total_size` bytes.
+    Unsatisfiable {
+        total_size: u64,
+    },
+}
+
 #[derive(NetworkBehaviour)]
 struct Behaviour {
     identify: identify::Behaviour,
     bitswap: beetswap::Behaviour<64, SledBlockstore>,
     mdns: mdns::tokio::Behaviour,
-    kademlia: kad::Behaviour<MemoryStore>,
+    kademlia: kad::Behaviour<SledRecordStore>,
 }
 
+/// Handle to the network event loop. Cloning is cheap and every clone talks to
+/// the same swarm, so callers can issue requests concurrently.
+#[derive(Clone)]
 pub struct P2PCDNClient {
     blockstore: Arc<SledBlockstore>,
-    command_sender: mpsc::Sender<Command>,
+    /// Recently read blocks, shared by every clone of the client.
+    cache: Arc<BlockCache>,
+    pins: PinStore,
+    access: AccessLog,
+    /// Held for writing by garbage collection and for reading while a block
+    /// is stored or a pin recorded, so a pass never sees half of either.
+    gc_lock: Arc<RwLock<()>>,
+    /// Blocks of uploads and pins still in progress, kept by garbage
+    /// collection until their DAG is pinned.
+    pending: PendingBlocks,
+    /// Bytes the blockstore may hold before unpinned blocks are evicted.
+    storage_quota: Option<u64>,
+    command_sender: tokio::sync::mpsc::Sender<Command>,
+    event_sender: broadcast::Sender<NetworkEvent>,
+    metrics: Arc<Metrics>,
+    /// Cleared by the supervisor once the event loop has exited.
+    running: Arc<AtomicBool>,
+    fetch_timeout: Duration,
+    chunk_size: usize,
+    max_upload_size: u64,
 }
 
 impl P2PCDNClient {
     pub async fn new(
-        bootstrap_peers: Option<Vec<Multiaddr>>,
+        config: &NetworkConfig,
         secret_key_seed: Option<u8>,
-    ) -> std::result::Result<
-        (P2PCDNClient, impl Stream<Item = kad::Event>, EventLoop),
-        Box<dyn Error>,
-    > {
+        metrics: Arc<Metrics>,
+    ) -> std::result::Result<(P2PCDNClient, EventLoop), Box<dyn Error>> {
+        let path = &config.data_dir;
         let id_keys = match secret_key_seed {
             Some(seed) => {
                 let mut bytes = [0u8; 32];
                 bytes[0] = seed;
                 identity::Keypair::ed25519_from_bytes(bytes)?
             }
-            None => load_or_generate_keypair(),
+            None => load_or_generate_keypair(path)?,
         };
 
         let peer_id = id_keys.public().to_peer_id();
-        let path = boxpeer_dir().await.expect("Error with cache");
         let db: sled::Db;
 
         loop {
-            match sled::open(&path) {
+            match sled::open(path) {
                 Ok(opened_db) => {
                     db = opened_db;
                     break;
                 }
                 Err(_) => {
-                    let fallback_path = format!("{}_fallback", path);
+                    let mut fallback_path = path.clone().into_os_string();
+                    fallback_path.push("_fallback");
+                    let fallback_path = PathBuf::from(fallback_path);
                     info!(
                         "DB is still locked, falling back to DB2 at {}",
-                        fallback_path
+                        fallback_path.display()
                     );
                     db = sled::open(fallback_path)?;
                     break;
@@ -99,29 +371,36 @@ impl P2PCDNClient {
             id_keys.public().clone(),
         ));
 
-        let blockstore = Arc::new(SledBlockstore::new(db).await.expect("Err"));
+        let blockstore = Arc::new(SledBlockstore::new(db.clone()).await?);
+        let pins = PinStore::open(&db)?;
+        let access = AccessLog::open(&db)?;
+        let record_store = SledRecordStore::open(&db, peer_id)?;
+        let routing = RoutingSnapshot::open(&db)?;
         let mut cfg = kad::Config::new(BOXPEER_PROTO_NAME);
 
         cfg.set_periodic_bootstrap_interval(Some(Duration::from_secs(60)));
         cfg.set_record_ttl(None);
+        if !config.republish_providers {
+            // The reprovider republishes the node's content on its own schedule
+            cfg.set_provider_publication_interval(None);
+        }
 
         let mut swarm = SwarmBuilder::with_existing_identity(id_keys)
             .with_tokio()
             .with_quic()
-            .with_behaviour(|key| Behaviour {
-                kademlia: kad::Behaviour::with_config(
-                    peer_id,
-                    MemoryStore::new(key.public().to_peer_id()),
-                    cfg,
-                ),
-                mdns: mdns::tokio::Behaviour::new(
-                    mdns::Config::default(),
-                    key.public().to_peer_id(),
-                )
-                .expect("Error with mdns configuring"),
-                bitswap: beetswap::Behaviour::new(blockstore.clone()),
-                identify,
-            })?
+            .with_behaviour(
+                |key| -> std::result::Result<_, Box<dyn Error + Send + Sync>> {
+                    Ok(Behaviour {
+                        kademlia: kad::Behaviour::with_config(peer_id, record_store, cfg),
+                        mdns: mdns::tokio::Behaviour::new(
+                            mdns::Config::default(),
+                            key.public().to_peer_id(),
+                        )?,
+                        bitswap: beetswap::Behaviour::new(blockstore.clone()),
+                        identify,
+                    })
+                },
+            )?
             .with_swarm_config(|cfg| {
                 cfg.with_idle_connection_timeout(Duration::from_secs(u64::MAX))
             })
@@ -132,172 +411,793 @@ impl P2PCDNClient {
             .kademlia
             .set_mode(Some(kad::Mode::Server));
 
-        let address: Multiaddr = "/ip4/0.0.0.0/udp/9090/quic-v1"
-            .parse()
-            .expect("Error with address");
-        swarm.listen_on(address).expect("Error listening");
-
-        // Dial bootstrap peers if provided
-        if let Some(peers) = bootstrap_peers {
-            for peer in peers {
-                if let Err(e) = swarm.dial(peer.clone()) {
-                    eprintln!("Failed to dial peer {}: {}", peer, e);
-                } else {
-                    println!("Dialing bootstrap peer: {}", peer);
-                }
+        let known_peers = routing.load()?;
+        for (peer_id, addresses) in &known_peers {
+            for address in addresses {
+                swarm
+                    .behaviour_mut()
+                    .kademlia
+                    .add_address(peer_id, address.clone());
+            }
+        }
+        if !known_peers.is_empty() {
+            info!(
+                "Restored {} peers into the routing table",
+                known_peers.len()
+            );
+        }
+
+        for address in &config.listen_addrs {
+            swarm
+                .listen_on(address.clone())
+                .map_err(|e| anyhow!("Failed to listen on {}: {}", address, e))?;
+        }
+
+        for peer in &config.bootstrap_peers {
+            if let Err(e) = swarm.dial(peer.clone()) {
+                warn!("Failed to dial bootstrap peer {}: {}", peer, e);
+            } else {
+                info!("Dialing bootstrap peer: {}", peer);
             }
         }
 
-        let (command_sender, command_receiver) = mpsc::channel(0);
-        let (event_sender, event_receiver) = mpsc::channel(0);
+        let (command_sender, command_receiver) = tokio::sync::mpsc::channel(COMMAND_BUFFER);
+        let (event_sender, _) = broadcast::channel(NETWORK_EVENT_BUFFER);
+        let running = Arc::new(AtomicBool::new(true));
         Ok((
             P2PCDNClient {
                 blockstore: blockstore.clone(),
+                cache: Arc::new(BlockCache::new(DEFAULT_CACHE_SIZE)),
+                pins,
+                access,
+                gc_lock: Default::default(),
+                pending: Default::default(),
+                storage_quota: None,
                 command_sender,
+                event_sender: event_sender.clone(),
+                metrics: metrics.clone(),
+                running: running.clone(),
+                fetch_timeout: DEFAULT_FETCH_TIMEOUT,
+                chunk_size: DEFAULT_CHUNK_SIZE,
+                max_upload_size: DEFAULT_MAX_UPLOAD_SIZE,
             },
-            event_receiver,
-            EventLoop::new(swarm, command_receiver, event_sender, blockstore),
+            EventLoop::new(
+                swarm,
+                command_receiver,
+                event_sender,
+                blockstore,
+                db,
+                running,
+                metrics,
+            ),
         ))
     }
 
-    pub(crate) async fn get_peers_count(
-        &mut self,
-    ) -> std::result::Result<Vec<PeerId>, Box<dyn Error + Send>> {
+    /// Sets how long each block fetch, and each `request_file` read as a
+    /// whole, may take for calls made through this client (and clones made
+    /// from it afterwards).
+    pub fn with_fetch_timeout(mut self, fetch_timeout: Duration) -> Self {
+        self.fetch_timeout = fetch_timeout;
+        self
+    }
+
+    /// Sets the leaf block size used when importing files.
+    pub fn with_chunk_size(mut self, chunk_size: usize) -> Self {
+        self.chunk_size = chunk_size;
+        self
+    }
+
+    /// Sets the largest file accepted by `upload_reader` and `upload_stream`.
+    pub fn with_max_upload_size(mut self, max_upload_size: u64) -> Self {
+        self.max_upload_size = max_upload_size;
+        self
+    }
+
+    /// Sets how many bytes of recently read blocks are kept in memory; 0
+    /// turns the cache off.
+    pub fn with_cache_size(mut self, cache_size: usize) -> Self {
+        self.cache = Arc::new(BlockCache::new(cache_size));
+        self
+    }
+
+    /// Sets the bytes the blockstore may hold before garbage collection
+    /// evicts unpinned blocks; `None` leaves it unbounded.
+    pub fn with_storage_quota(mut self, storage_quota: Option<u64>) -> Self {
+        self.storage_quota = storage_quota;
+        self
+    }
+
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Subscribes to events from the network. Every subscriber gets its own
+    /// copy of each event published after it subscribed; one that falls
+    /// more than `NETWORK_EVENT_BUFFER` events behind skips the oldest.
+    pub fn subscribe_events(&self) -> broadcast::Receiver<NetworkEvent> {
+        self.event_sender.subscribe()
+    }
+
+    /// Whether the network event loop is still running. Once it has stopped
+    /// every request fails with `NetError::EventLoopStopped`.
+    pub fn is_running(&self) -> bool {
+        self.running.load(Ordering::SeqCst)
+    }
+
+    /// Writes out buffered access times and asks the event loop to flush
+    /// the blockstore and exit. Requests still waiting on the network are
+    /// failed.
+    pub async fn shutdown(&self) -> Result<(), NetError> {
+        if let Err(e) = self.access.flush() {
+            warn!("Failed to save block access times: {}", e);
+        }
         let (sender, receiver) = oneshot::channel();
         self.command_sender
-            .send(Command::GetPeers { sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .send(Command::Shutdown { sender })
+            .await?;
+        receiver.await?
     }
 
-    pub(crate) async fn start_listening(&mut self, addr: Multiaddr) -> Result<String> {
+    pub async fn status(&self) -> Result<NodeStatus, NetError> {
         let (sender, receiver) = oneshot::channel();
         self.command_sender
-            .send(Command::StartListening { addr, sender })
-            .await
-            .expect("Command receiver not to be dropped.");
-        receiver.await.expect("Sender not to be dropped.")
+            .send(Command::GetStatus { sender })
+            .await?;
+        receiver.await?
     }
 
-    pub async fn upload_file(&mut self, file_path: PathBuf) -> Result<String> {
+    pub(crate) async fn start_listening(&self, addr: Multiaddr) -> Result<String, NetError> {
         let (sender, receiver) = oneshot::channel();
         self.command_sender
-            .send(Command::UploadFile { file_path, sender })
+            .send(Command::StartListening { addr, sender })
             .await?;
+        receiver.await?
+    }
+
+    pub async fn upload_file(&self, file_path: PathBuf) -> Result<String> {
+        let file = tokio::fs::File::open(&file_path)
+            .await
+            .map_err(|e| anyhow!("Failed to read file from {:?}: {:?}", file_path, e))?;
+        let uploaded = self.upload_reader(file).await?;
+        Ok(uploaded.cid.to_string())
+    }
+
+    /// Imports a file from `reader` without holding it in memory. Blocks are
+    /// hashed and stored as they complete, on the caller's task, and the root
+    /// is announced to the network once the whole file is stored.
+    pub async fn upload_reader<R: AsyncRead + Unpin>(&self, mut reader: R) -> Result<UploadedFile> {
+        let hold = self.pending.hold();
+        let mut importer = FileImporter::new(self.chunk_size);
+        let mut buf = vec![0u8; UPLOAD_READ_BUFFER_SIZE];
+        let mut size = 0u64;
+        loop {
+            let read = reader.read(&mut buf).await?;
+            if read == 0 {
+                break;
+            }
+            size = self.check_upload_size(size, read)?;
+            self.put_blocks(&hold, importer.push(&buf[..read])).await?;
+        }
+        self.finish_upload(&hold, importer, size).await
+    }
+
+    /// Like `upload_reader`, for content arriving as a stream of byte chunks.
+    pub async fn upload_stream<S, B, E>(&self, stream: S) -> Result<UploadedFile>
+    where
+        S: Stream<Item = std::result::Result<B, E>>,
+        B: AsRef<[u8]>,
+        E: Into<anyhow::Error>,
+    {
+        futures::pin_mut!(stream);
+        let hold = self.pending.hold();
+        let mut importer = FileImporter::new(self.chunk_size);
+        let mut size = 0u64;
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk.map_err(Into::into)?;
+            size = self.check_upload_size(size, chunk.as_ref().len())?;
+            self.put_blocks(&hold, importer.push(chunk.as_ref()))
+                .await?;
+        }
+        self.finish_upload(&hold, importer, size).await
+    }
+
+    /// The largest file accepted for upload, in bytes.
+    pub fn max_upload_size(&self) -> u64 {
+        self.max_upload_size
+    }
+
+    /// Adds `read` bytes to the size of an upload, failing once it passes
+    /// the limit. Blocks already stored are left for garbage collection.
+    fn check_upload_size(&self, size: u64, read: usize) -> Result<u64, NetError> {
+        let size = size + read as u64;
+        if size > self.max_upload_size {
+            return Err(NetError::UploadTooLarge {
+                limit: self.max_upload_size,
+            });
+        }
+        Ok(size)
+    }
+
+    async fn put_blocks(&self, hold: &PendingHold, blocks: Vec<(Cid, Vec<u8>)>) -> Result<()> {
+        let _gc = self.gc_lock.read().await;
+        for (cid, block) in blocks {
+            self.blockstore
+                .put_keyed(&cid, &block)
+                .await
+                .map_err(|e| anyhow!("Failed to store block: {:?}", e))?;
+            self.access.record(&cid, block.len() as u64)?;
+            hold.add(cid);
+        }
+        Ok(())
+    }
 
-        let cid = receiver.await??;
-        Ok(cid.to_string())
+    async fn finish_upload(
+        &self,
+        hold: &PendingHold,
+        importer: FileImporter,
+        size: u64,
+    ) -> Result<UploadedFile> {
+        // Leaves were stored before the nodes linking them, so the root is
+        // only stored once the whole file is.
+        let (cid, blocks) = importer.finish();
+        self.put_blocks(hold, blocks).await?;
+        info!("Uploaded file with CID: {} ({} bytes)", cid, size);
+
+        let pinned = self.keep_and_provide(cid).await?;
+        Ok(UploadedFile {
+            cid,
+            size,
+            announced: pinned.announced,
+        })
+    }
+
+    /// Looks up the peers providing `cid` on the DHT. Providers are yielded as
+    /// the Kademlia query discovers them and the stream ends with the query.
+    pub async fn find_providers(&self, cid: Cid) -> Result<mpsc::UnboundedReceiver<Provider>> {
+        let (sender, receiver) = mpsc::unbounded();
+        self.command_sender
+            .send(Command::GetProviders {
+                cid: RecordKey::new(&cid.to_bytes()),
+                sender,
+            })
+            .await
+            .map_err(NetError::from)?;
+        Ok(receiver)
     }
-    pub async fn get_all_files(&mut self, cids: Vec<Cid>) -> Result<Vec<Vec<u8>>> {
+
+    pub async fn get_all_files(&self, cids: Vec<Cid>) -> Result<Vec<Vec<u8>>> {
         let mut contents = Vec::new();
         for cid in cids {
-            let content = self.request_file(cid).await.expect("An error occurred");
+            let content = self.request_file(cid).await?;
             contents.push(content);
         }
         Ok(contents)
     }
 
-    pub async fn owned_file(&mut self, cid: Cid) -> Result<bool> {
-        if self
-            .blockstore
-            .has(&cid)
-            .await
-            .expect("Error with blockstore")
-        {
-            Ok(true)
-        } else {
-            Ok(false)
+    pub async fn owned_file(&self, cid: Cid) -> Result<bool> {
+        Ok(self.blockstore.has(&cid).await.map_err(NetError::from)?)
+    }
+
+    /// Reads a whole file into memory, giving up once the fetch timeout has
+    /// passed for the read as a whole.
+    pub async fn request_file(&self, cid: Cid) -> Result<Vec<u8>> {
+        let policy = CachePolicy::LocalFirst;
+        let deadline = Instant::now() + self.fetch_timeout;
+        self.with_deadline(cid, deadline, async {
+            let root = DagNode::decode(&cid, self.load_root(cid, policy).await?)?;
+            let file_size = root.file_size();
+            self.read_node(root, 0, file_size, policy).await
+        })
+        .await
+    }
+
+    /// Runs part of a read of `cid` until `deadline`. Giving up drops the
+    /// pending fetches, which the event loop then cancels.
+    async fn with_deadline<T>(
+        &self,
+        cid: Cid,
+        deadline: Instant,
+        read: impl Future<Output = Result<T>>,
+    ) -> Result<T> {
+        let deadline = tokio::time::Instant::from_std(deadline);
+        match tokio::time::timeout_at(deadline, read).await {
+            Ok(result) => result,
+            Err(_) => Err(NetError::TimedOut {
+                cid,
+                after: self.fetch_timeout,
+            }
+            .into()),
         }
     }
 
-    pub async fn request_file(&mut self, cid: Cid) -> Result<Vec<u8>> {
+    /// Loads the root block of a file, counting whether it was held locally.
+    async fn load_root(&self, cid: Cid, policy: CachePolicy) -> Result<Vec<u8>> {
+        let local = self.blockstore.has(&cid).await.map_err(NetError::from)?;
+        self.metrics.record_file_request(local);
+        if !local {
+            info!("CID {:?} not found in local blockstore.", cid);
+        }
+        self.load_block(cid, policy).await
+    }
 
-        if !self
-            .blockstore
-            .has(&cid)
+    /// Fetches a single block from the network.
+    async fn request_block(&self, cid: Cid) -> Result<Vec<u8>> {
+        let (sender, receiver) = oneshot::channel();
+        self.command_sender
+            .send(Command::RequestFile { cid, sender })
             .await
-            .expect("Error with blockstore")
+            .map_err(NetError::from)?;
+
+        // Dropping the receiver on timeout (or when the caller's future is
+        // dropped) lets the event loop cancel the query on its next sweep.
+        let started = Instant::now();
+        let result = match tokio::time::timeout(self.fetch_timeout, receiver).await {
+            Ok(block) => block.map_err(NetError::from).and_then(|block| block),
+            Err(_) => Err(NetError::TimedOut {
+                cid,
+                after: self.fetch_timeout,
+            }),
+        };
+        let outcome = match &result {
+            Ok(_) => FetchOutcome::Ok,
+            Err(NetError::TimedOut { .. }) => FetchOutcome::Timeout,
+            Err(_) => FetchOutcome::Error,
+        };
+        self.metrics.record_block_fetch(outcome, started.elapsed());
+        Ok(result?)
+    }
+
+    async fn load_block(&self, cid: Cid, policy: CachePolicy) -> Result<Vec<u8>> {
+        if policy == CachePolicy::LocalFirst {
+            let cached = self.cache.get(&cid);
+            self.metrics.record_cache_lookup(cached.is_some());
+            if let Some(block) = cached {
+                self.access.touch(&cid)?;
+                return Ok(block);
+            }
+        }
+        let block = self.read_block(cid, policy).await?;
+        self.cache.insert(cid, block.clone());
+        Ok(block)
+    }
+
+    async fn read_block(&self, cid: Cid, policy: CachePolicy) -> Result<Vec<u8>> {
+        if let Some(block) = self.blockstore.get(&cid).await.map_err(NetError::from)? {
+            if policy == CachePolicy::LocalFirst || block_matches(&cid, &block) {
+                self.access.touch(&cid)?;
+                return Ok(block);
+            }
+            // Bitswap would serve the bad copy again, so drop it first
+            warn!(
+                "Local block {} does not match its CID, fetching it again",
+                cid
+            );
+            self.cache.remove(&cid);
+            self.blockstore.remove(&cid).await.map_err(NetError::from)?;
+        }
+        // Bitswap stores the blocks it receives
+        let block = self.request_block(cid).await?;
+        self.access.record(&cid, block.len() as u64)?;
+        Ok(block)
+    }
+
+    /// Reads bytes `start..end` of the file below `node`, fetching only the
+    /// blocks that overlap the range.
+    async fn read_node(
+        &self,
+        node: DagNode,
+        start: u64,
+        end: u64,
+        policy: CachePolicy,
+    ) -> Result<Vec<u8>> {
+        // Sizes come from the network, so a small block can claim any size;
+        // the rest of the buffer grows with the blocks that arrive.
+        let mut content = Vec::with_capacity((end - start).min(MAX_PREALLOCATION) as usize);
+        let mut parts = self.read_parts(node, start, end, policy);
+        while let Some(part) = parts.try_next().await? {
+            content.extend_from_slice(&part);
+        }
+        if content.len() as u64 != end - start {
+            return Err(anyhow!(
+                "Read {} bytes of a range of {}",
+                content.len(),
+                end - start
+            ));
+        }
+        Ok(content)
+    }
+
+    /// Bytes `start..end` of the file below `node` in order, read with
+    /// `dag::read_range` from local blocks and the network.
+    fn read_parts(
+        &self,
+        node: DagNode,
+        start: u64,
+        end: u64,
+        policy: CachePolicy,
+    ) -> BoxStream<'static, Result<Vec<u8>>> {
+        let client = self.clone();
+        read_range(node, start, end, DAG_FETCH_CONCURRENCY, move |child| {
+            let client = client.clone();
+            let cid = child.cid;
+            async move { DagNode::decode(&cid, client.load_block(cid, policy).await?) }.boxed()
+        })
+    }
+
+    /// Reads a whole file as a stream of parts for sending it on as they
+    /// arrive. Unlike `request_file`, there is no deadline for the whole
+    /// file, since the consumer sets the pace; each block fetch still gives
+    /// up after the fetch timeout.
+    pub async fn stream_file(&self, cid: Cid, policy: CachePolicy) -> Result<FileStream> {
+        let root = DagNode::decode(&cid, self.load_root(cid, policy).await?)?;
+        let total_size = root.file_size();
+        Ok(FileStream {
+            total_size,
+            parts: self.read_parts(root, 0, total_size, policy),
+        })
+    }
+
+    /// Reads part of a file as a stream of parts, like `stream_file`.
+    /// `resolve` maps the file's total size to the inclusive `(first, last)`
+    /// byte positions to return, or `None` when the range cannot be
+    /// satisfied.
+    pub async fn request_file_range<F>(
+        &self,
+        cid: Cid,
+        policy: CachePolicy,
+        resolve: F,
+    ) -> Result<RangeResult>
+    where
+        F: FnOnce(u64) -> Option<(u64, u64)>,
+    {
+        // Only the blocks of a chunked file that cover the range are read
+        let root = DagNode::decode(&cid, self.load_root(cid, policy).await?)?;
+        let total_size = root.file_size();
+        let Some((first, last)) =
+            resolve(total_size).filter(|(first, last)| first <= last && *last < total_size)
+        else {
+            return Ok(RangeResult::Unsatisfiable { total_size });
+        };
+        Ok(RangeResult::Partial(FileRange {
+            total_size,
+            start: first,
+            len: last - first + 1,
+            parts: self.read_parts(root, first, last + 1, policy),
+        }))
+    }
+
+    /// The first `len` bytes of a file, or all of it when it is shorter.
+    pub async fn read_head(&self, cid: Cid, policy: CachePolicy, len: u64) -> Result<Vec<u8>> {
+        let root = DagNode::decode(&cid, self.load_block(cid, policy).await?)?;
+        let end = root.file_size().min(len);
+        self.read_node(root, 0, end, policy).await
+    }
+
+    pub async fn lock_file(&self, cid: Cid) -> Result<String, anyhow::Error> {
+        if self.pins.contains(&cid)? {
+            return Ok("You are already providing this file".to_string());
+        }
+        self.pin(cid).await?;
+
+        Ok(format!("You are now providing file {:?}", &cid))
+    }
+
+    /// Pins `cid`, first fetching whatever part of its DAG is not stored
+    /// yet, and announces it.
+    pub async fn pin(&self, cid: Cid) -> Result<Pinned> {
+        let hold = self.pending.hold();
+        self.store_dag(cid, &hold).await?;
+        self.keep_and_provide(cid).await
+    }
+
+    /// Pins a DAG that is fully stored and announces it on the DHT, waiting
+    /// until the announcement has reached the closest peers. Uploads and
+    /// pins both go through here. Only a failure to pin is an error, the
+    /// announcement is reported alongside.
+    async fn keep_and_provide(&self, cid: Cid) -> Result<Pinned> {
         {
-            info!("CID {:?} not found in local blockstore.", cid);
+            let _gc = self.gc_lock.read().await;
+            self.pins.insert(&cid)?;
         }
+        let announced = self.provide(cid).await;
+        match &announced {
+            Ok(()) => info!("Providing {}", cid),
+            Err(e) => warn!("Pinned {} but could not announce it: {}", cid, e),
+        }
+        Ok(Pinned { announced })
+    }
 
+    async fn provide(&self, cid: Cid) -> Result<(), NetError> {
         let (sender, receiver) = oneshot::channel();
         self.command_sender
-            .send(Command::RequestFile { cid, sender })
+            .send(Command::StartProviding { cid, sender })
             .await?;
+        receiver.await?
+    }
 
-        let file_data = receiver.await??;
-        // println!("{:?}", &file_data);
-        Ok(file_data)
+    /// Announces stored content again once the node has reached the network
+    /// and then every `config.interval`, since provider records expire. Meant
+    /// for a node started with `republish_providers` off.
+    pub fn spawn_reprovider(&self, config: ReprovideConfig) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            while !matches!(client.status().await, Ok(status) if status.ready) {
+                if !client.is_running() {
+                    return;
+                }
+                tokio::time::sleep(REPROVIDE_READY_POLL).await;
+            }
+            let mut ticks = tokio::time::interval(config.interval);
+            while client.is_running() {
+                ticks.tick().await;
+                if let Err(e) = client.reprovide(config).await {
+                    warn!("Reproviding failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Announces every CID `config.strategy` selects, `config.batch_size` at
+    /// a time with a pause between batches so large stores do not flood
+    /// the DHT. Returns how many announcements succeeded.
+    async fn reprovide(&self, config: ReprovideConfig) -> Result<usize, NetError> {
+        let cids = match config.strategy {
+            ReprovideStrategy::Pinned => self.pins.list()?.into_iter().map(|pin| pin.cid).collect(),
+            ReprovideStrategy::All => self.stored_roots().await?,
+        };
+        info!("Reproviding {} CIDs", cids.len());
+
+        let (mut provided, mut failed) = (0, 0);
+        for (i, batch) in cids.chunks(config.batch_size).enumerate() {
+            if i > 0 {
+                tokio::time::sleep(REPROVIDE_BATCH_PAUSE).await;
+            }
+            let outcomes =
+                futures::future::join_all(batch.iter().map(|cid| self.provide(*cid))).await;
+            for outcome in outcomes {
+                match outcome {
+                    Ok(()) => provided += 1,
+                    Err(NetError::EventLoopStopped) => return Err(NetError::EventLoopStopped),
+                    Err(e) => {
+                        debug!("{}", e);
+                        failed += 1;
+                    }
+                }
+            }
+        }
+        if failed > 0 {
+            warn!("Reprovided {} CIDs, {} failed", provided, failed);
+        } else {
+            info!("Reprovided {} CIDs", provided);
+        }
+        Ok(provided)
     }
 
-    pub async fn lock_file(&mut self, cid: Cid) -> Result<String, anyhow::Error> {
-        // Check if the file exists in the local blockstore
-        if let Ok(true) = self.blockstore.has(&cid).await {
-            // File already exists locally, retrieve it
-            if let Ok(_data) = self.blockstore.get(&cid).await {
-                return Ok("You are already providing this file".to_string());
+    /// Stored blocks no other stored block links to: the roots of stored
+    /// files, and of the parts of files that were read.
+    async fn stored_roots(&self) -> Result<Vec<Cid>, NetError> {
+        let stored = self.access.stored_cids()?;
+        let mut linked = HashSet::new();
+        for cid in stored.iter().filter(|cid| cid.codec() == DAG_PB_CODEC) {
+            let Some(block) = self.blockstore.get(cid).await? else {
+                continue;
+            };
+            if let Ok(DagNode::File { children, .. }) = DagNode::decode(cid, block) {
+                linked.extend(children.into_iter().map(|child| child.cid));
             }
         }
+        Ok(stored
+            .into_iter()
+            .filter(|cid| !linked.contains(cid))
+            .collect())
+    }
 
-        // File not found in local blockstore, request it from peers
+    /// Removes the pin of `cid` and stops announcing it. The blocks stay
+    /// until they are collected. Returns false when `cid` was not pinned.
+    pub async fn unpin(&self, cid: Cid) -> Result<bool> {
+        if !self.pins.remove(&cid)? {
+            return Ok(false);
+        }
         let (sender, receiver) = oneshot::channel();
         self.command_sender
-            .send(Command::RequestFile { cid, sender })
+            .send(Command::StopProviding { cid, sender })
             .await
-            .map_err(|e| anyhow!("Failed to send request for file: {:?}", e))?;
-        let file_data = receiver.await??;
+            .map_err(NetError::from)?;
+        receiver.await.map_err(NetError::from)??;
+        Ok(true)
+    }
 
-        // Store the retrieved file in the local blockstore
-        self.blockstore
-            .put_keyed(&cid, &file_data)
-            .await
-            .map_err(|e| anyhow!("Failed to store block in blockstore: {:?}", e))?;
+    pub fn list_pins(&self) -> Result<Vec<Pin>, NetError> {
+        self.pins.list()
+    }
 
-        Ok(format!("You are now providing file {:?}", &cid))
+    /// Removes unpinned blocks, least recently used first, until the
+    /// blockstore fits the storage quota. Without a quota every unpinned
+    /// block is removed. Blocks reachable from a pin or used within
+    /// `GC_GRACE_PERIOD` are never touched.
+    pub async fn collect_garbage(&self) -> Result<GcReport> {
+        let _gc = self.gc_lock.write().await;
+        let target = self.storage_quota.unwrap_or(0);
+        let pinned = self.pinned_blocks().await?;
+        let blocks = self.access.blocks()?;
+        let mut report = GcReport {
+            stored_bytes: blocks.iter().map(|block| block.size).sum(),
+            ..Default::default()
+        };
+
+        let evicted = select_evictions(blocks, report.stored_bytes, target, unix_secs(), |cid| {
+            pinned.contains(cid) || self.pending.contains(cid)
+        });
+        for block in evicted {
+            self.blockstore
+                .remove(&block.cid)
+                .await
+                .map_err(NetError::from)?;
+            self.cache.remove(&block.cid);
+            self.access.remove(&block.cid)?;
+            report.removed_blocks += 1;
+            report.freed_bytes += block.size;
+            report.stored_bytes -= block.size;
+        }
+
+        self.metrics.record_gc(report.freed_bytes);
+        info!(
+            "Garbage collection removed {} blocks ({} bytes), {} bytes stored",
+            report.removed_blocks, report.freed_bytes, report.stored_bytes
+        );
+        Ok(report)
     }
 
+    /// Runs garbage collection now and then every `interval` until the
+    /// network stops.
+    pub fn spawn_gc(&self, interval: Duration) -> JoinHandle<()> {
+        let client = self.clone();
+        tokio::spawn(async move {
+            let mut ticks = tokio::time::interval(interval);
+            while client.is_running() {
+                ticks.tick().await;
+                if let Err(e) = client.collect_garbage().await {
+                    warn!("Garbage collection failed: {}", e);
+                }
+            }
+        })
+    }
+
+    /// Every local block reachable from a pin.
+    async fn pinned_blocks(&self) -> Result<HashSet<Cid>> {
+        let mut pinned = HashSet::new();
+        let mut pending: Vec<Cid> = self.pins.list()?.into_iter().map(|pin| pin.cid).collect();
+        while let Some(cid) = pending.pop() {
+            if !pinned.insert(cid) {
+                continue;
+            }
+            let Some(block) = self.blockstore.get(&cid).await.map_err(NetError::from)? else {
+                continue;
+            };
+            match DagNode::decode(&cid, block) {
+                Ok(DagNode::File { children, .. }) => {
+                    pending.extend(children.into_iter().map(|child| child.cid))
+                }
+                Ok(DagNode::Leaf(_)) => {}
+                Err(e) => warn!("Cannot follow the links of pinned block {}: {}", cid, e),
+            }
+        }
+        Ok(pinned)
+    }
+
+    /// Makes sure every block of the DAG below `cid` is in the local
+    /// blockstore, fetching the missing ones, and holds them until the pin
+    /// is recorded. Blocks fetched for a range request can leave a root
+    /// without all of its children, so the whole DAG is walked even when
+    /// the root is present.
+    fn store_dag<'a>(&'a self, cid: Cid, hold: &'a PendingHold) -> BoxFuture<'a, Result<()>> {
+        async move {
+            let local = {
+                let _gc = self.gc_lock.read().await;
+                let block = self.blockstore.get(&cid).await.map_err(NetError::from)?;
+                if let Some(block) = &block {
+                    self.access.record(&cid, block.len() as u64)?;
+                    hold.add(cid);
+                }
+                block
+            };
+            let block = match local {
+                Some(block) => block,
+                None => {
+                    let block = self.request_block(cid).await?;
+                    let _gc = self.gc_lock.read().await;
+                    self.blockstore
+                        .put_keyed(&cid, &block)
+                        .await
+                        .map_err(|e| anyhow!("Failed to store block in blockstore: {:?}", e))?;
+                    self.access.record(&cid, block.len() as u64)?;
+                    hold.add(cid);
+                    block
+                }
+            };
+            if let DagNode::File { children, .. } = DagNode::decode(&cid, block)? {
+                futures::stream::iter(children)
+                    .map(|child| self.store_dag(child.cid, hold))
+                    .buffer_unordered(DAG_FETCH_CONCURRENCY)
+                    .try_collect::<Vec<()>>()
+                    .await?;
+            }
+            Ok(())
+        }
+        .boxed()
+    }
 }
 pub enum Command {
     StartListening {
         addr: Multiaddr,
-        sender: oneshot::Sender<Result<String>>,
+        sender: oneshot::Sender<Result<String, NetError>>,
     },
-    UploadFile {
-        file_path: PathBuf,
-        sender: oneshot::Sender<Result<Cid>>,
+    /// Answered once the provider record has been stored on the closest
+    /// peers, or the announcement failed.
+    StartProviding {
+        cid: Cid,
+        sender: oneshot::Sender<Result<(), NetError>>,
+    },
+    StopProviding {
+        cid: Cid,
+        sender: oneshot::Sender<Result<(), NetError>>,
     },
     RequestFile {
         cid: Cid,
-        sender: oneshot::Sender<Result<Vec<u8>>>,
+        sender: BlockSender,
     },
     GetProviders {
         cid: RecordKey,
-        sender: oneshot::Sender<HashSet<PeerId>>,
+        sender: mpsc::UnboundedSender<Provider>,
+    },
+    GetStatus {
+        sender: oneshot::Sender<Result<NodeStatus, NetError>>,
     },
-    GetPeers {
-        sender: oneshot::Sender<std::result::Result<Vec<PeerId>, Box<dyn Error + Send>>>,
+    Shutdown {
+        sender: oneshot::Sender<Result<(), NetError>>,
     },
 }
 
+/// Where the event loop delivers a fetched block.
+type BlockSender = oneshot::Sender<Result<Vec<u8>, NetError>>;
+
+/// A `find_providers` lookup and the providers already reported to it.
+struct ProviderSearch {
+    sender: mpsc::UnboundedSender<Provider>,
+    reported: HashSet<PeerId>,
+}
+
 pub struct EventLoop {
     swarm: Swarm<Behaviour>,
-    command_receiver: mpsc::Receiver<Command>,
-    event_sender: mpsc::Sender<kad::Event>,
-    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), Box<dyn Error + Send>>>>,
+    command_receiver: tokio::sync::mpsc::Receiver<Command>,
+    event_sender: broadcast::Sender<NetworkEvent>,
+    pending_dial: HashMap<PeerId, oneshot::Sender<Result<(), NetError>>>,
     queries: HashMap<beetswap::QueryId, Cid>,
+    /// The bitswap query fetching each CID, shared by every caller asking
+    /// for it while it runs.
+    in_flight: HashMap<Cid, beetswap::QueryId>,
     kad_queries: HashMap<libp2p_kad::QueryId, Cid>,
-    pending_requests: HashMap<beetswap::QueryId, oneshot::Sender<Result<Vec<u8>>>>,
-    pending_get_providers: HashMap<kad::QueryId, oneshot::Sender<HashSet<PeerId>>>,
+    pending_requests: HashMap<beetswap::QueryId, Vec<BlockSender>>,
+    pending_get_providers: HashMap<kad::QueryId, ProviderSearch>,
+    pending_provides: HashMap<kad::QueryId, (Cid, oneshot::Sender<Result<(), NetError>>)>,
     blockstore: Arc<SledBlockstore>,
+    /// The database behind `blockstore`, flushed on shutdown.
+    db: sled::Db,
+    running: Arc<AtomicBool>,
+    metrics: Arc<Metrics>,
+    started_at: Instant,
+    /// Set by the first established connection or successful bootstrap.
+    reached_network: bool,
 }
 impl EventLoop {
     pub(crate) fn new(
         swarm: Swarm<Behaviour>,
-        command_receiver: mpsc::Receiver<Command>,
-        event_sender: mpsc::Sender<kad::Event>,
+        command_receiver: tokio::sync::mpsc::Receiver<Command>,
+        event_sender: broadcast::Sender<NetworkEvent>,
         blockstore: Arc<SledBlockstore>,
+        db: sled::Db,
+        running: Arc<AtomicBool>,
+        metrics: Arc<Metrics>,
     ) -> Self {
         Self {
             swarm,
@@ -305,32 +1205,47 @@ impl EventLoop {
             event_sender,
             pending_dial: Default::default(),
             queries: Default::default(),
+            in_flight: Default::default(),
             kad_queries: Default::default(),
             pending_requests: Default::default(),
             pending_get_providers: Default::default(),
+            pending_provides: Default::default(),
             blockstore,
+            db,
+            running,
+            metrics,
+            started_at: Instant::now(),
+            reached_network: false,
         }
     }
 
-    async fn handle_event(
-        &mut self,
-        event: SwarmEvent<BehaviourEvent>,
-    ) -> Result<(), anyhow::Error> {
+    async fn handle_event(&mut self, event: SwarmEvent<BehaviourEvent>) -> Result<(), NetError> {
+        self.metrics.record(&event);
         match event {
             SwarmEvent::Behaviour(BehaviourEvent::Bitswap(bitswap)) => match bitswap {
                 beetswap::Event::GetQueryResponse { query_id, data } => {
-                    self.queries.get(&query_id);
-                    if let Some(sender) = self.pending_requests.remove(&query_id) {
-                        sender
-                            .send(Ok(data))
-                            .map_err(|e| anyhow!("Failed to send file data: {:?}", e))?;
+                    let cid = self.finish_query(query_id);
+                    if let Some(cid) = cid {
+                        self.emit(NetworkEvent::BlockReceived {
+                            cid,
+                            size: data.len(),
+                        });
+                    }
+                    for sender in self.pending_requests.remove(&query_id).unwrap_or_default() {
+                        reply(sender, "block", cid, Ok(data.clone()));
                     }
                 }
                 beetswap::Event::GetQueryError { query_id, error } => {
-                    if let Some(sender) = self.pending_requests.remove(&query_id) {
-                        sender
-                            .send(Err(anyhow!("Error for CID {:?}: {:?}", query_id, error)))
-                            .map_err(|e| anyhow!("Failed to send error: {:?}", e))?;
+                    let cid = self.finish_query(query_id);
+                    let error = match cid {
+                        Some(cid) => NetError::FetchFailed {
+                            cid,
+                            reason: error.to_string(),
+                        },
+                        None => NetError::Blockstore(error.to_string()),
+                    };
+                    for sender in self.pending_requests.remove(&query_id).unwrap_or_default() {
+                        reply(sender, "block", cid, Err(error.clone()));
                     }
                 }
             },
@@ -345,42 +1260,73 @@ impl EventLoop {
                     }
                 }
             }
-            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad_event)) => match kad_event {
-                kad::Event::RoutingUpdated {
-                    peer, addresses, ..
-                } => {
-                    if let address = addresses.first() {
+            SwarmEvent::Behaviour(BehaviourEvent::Identify(identify_event)) => {
+                self.metrics.record(&identify_event);
+            }
+            SwarmEvent::Behaviour(BehaviourEvent::Kademlia(kad_event)) => {
+                self.metrics.record(&kad_event);
+                match kad_event {
+                    kad::Event::RoutingUpdated {
+                        peer, addresses, ..
+                    } => {
+                        self.emit(NetworkEvent::RoutingUpdated {
+                            peer_id: peer,
+                            addresses: addresses.iter().cloned().collect(),
+                        });
+                        let address = addresses.first();
                         info!("Discovered peer via Kademlia: {:?} at {:?}", peer, address);
                         match self.swarm.dial(address.clone()) {
                             Ok(()) => {
-                                info!("Dialing peer: {:?}\n", peer);
+                                info!("Dialing peer: {:?}", peer);
                             }
                             Err(e) => {
-                                warn!("Error Dialing peer: {:?}\n", e);
+                                warn!("Error Dialing peer: {:?}", e);
                             }
                         }
                     }
-                }
-                kad::Event::OutboundQueryProgressed { id, result, .. } => {
-                    if let kad::QueryResult::GetProviders(Ok(
-                        kad::GetProvidersOk::FoundProviders { providers, .. },
-                    )) = result
-                    {
-                        if let Some(sender) = self.pending_get_providers.remove(&id) {
-                            sender.send(providers).expect("Receiver not to be dropped");
-                            self.swarm
-                                .behaviour_mut()
-                                .kademlia
-                                .query_mut(&id)
-                                .unwrap()
-                                .finish();
+                    kad::Event::OutboundQueryProgressed {
+                        id, result, step, ..
+                    } => {
+                        if step.last {
+                            self.kad_queries.remove(&id);
+                        }
+                        if let kad::QueryResult::Bootstrap(Ok(_)) = result {
+                            self.reached_network = true;
+                        }
+                        if let kad::QueryResult::StartProviding(outcome) = &result {
+                            if let Some((cid, sender)) = self.pending_provides.remove(&id) {
+                                let outcome =
+                                    outcome.as_ref().map(|_| ()).map_err(|e| NetError::Provide {
+                                        cid,
+                                        reason: e.to_string(),
+                                    });
+                                reply(sender, "start providing", Some(cid), outcome);
+                            }
+                        }
+                        if let kad::QueryResult::GetProviders(Ok(
+                            kad::GetProvidersOk::FoundProviders { key, providers },
+                        )) = result
+                        {
+                            if let Ok(cid) = Cid::try_from(key.to_vec()) {
+                                for peer_id in &providers {
+                                    self.emit(NetworkEvent::ProviderFound {
+                                        cid,
+                                        peer_id: *peer_id,
+                                    });
+                                }
+                            }
+                            self.report_providers(id, providers);
+                        }
+                        // Dropping the sender ends the caller's stream
+                        if step.last {
+                            self.pending_get_providers.remove(&id);
                         }
                     }
+                    _ => {
+                        info!("Other Kademlia event: {:?}", kad_event);
+                    }
                 }
-                _ => {
-                    info!("Other Kademlia event: {:?}", kad_event);
-                }
-            },
+            }
             SwarmEvent::OutgoingConnectionError { peer_id, error, .. } => {
                 warn!(
                     "Failed to connect to peer: {:?}, error: {:?}",
@@ -388,8 +1334,17 @@ impl EventLoop {
                 );
             }
             SwarmEvent::ConnectionEstablished {
-                peer_id, endpoint, ..
+                peer_id,
+                endpoint,
+                num_established,
+                ..
             } => {
+                if num_established.get() == 1 {
+                    self.emit(NetworkEvent::PeerConnected { peer_id });
+                }
+                self.metrics
+                    .set_connected_peers(self.swarm.connected_peers().count());
+                self.reached_network = true;
                 if endpoint.is_dialer() {
                     if let Some(sender) = self.pending_dial.remove(&peer_id) {
                         let _ = sender.send(Ok(()));
@@ -398,6 +1353,9 @@ impl EventLoop {
                 info!("Connection established with peer: {:?}", peer_id);
             }
             SwarmEvent::NewListenAddr { address, .. } => {
+                self.emit(NetworkEvent::ListenAddrAdded {
+                    address: address.clone(),
+                });
                 let local_peer_id = *self.swarm.local_peer_id();
                 info!(
                     "Local node is listening on {:?}",
@@ -405,8 +1363,18 @@ impl EventLoop {
                 );
             }
 
-            SwarmEvent::ConnectionClosed { peer_id, cause, .. } => {
-                println!(
+            SwarmEvent::ConnectionClosed {
+                peer_id,
+                cause,
+                num_established,
+                ..
+            } => {
+                if num_established == 0 {
+                    self.emit(NetworkEvent::PeerDisconnected { peer_id });
+                }
+                self.metrics
+                    .set_connected_peers(self.swarm.connected_peers().count());
+                info!(
                     "Connection closed with peer: {:?}, reason: {:?}",
                     peer_id, cause
                 );
@@ -439,23 +1407,35 @@ impl EventLoop {
                 info!("Dialing peer: {:?}", peer_id);
             }
             SwarmEvent::ExpiredListenAddr { address, .. } => {
-                println!("Listen address expired: {:?}", address);
+                self.emit(NetworkEvent::ListenAddrRemoved {
+                    address: address.clone(),
+                });
+                warn!("Listen address expired: {:?}", address);
                 // Attempt to listen again with the original address
                 if let Err(e) = self.swarm.listen_on(address.clone()) {
-                    println!("Error listening on expired address: {:?}, trying a different port. Error: {:?}", address, e);
+                    warn!("Error listening on expired address: {:?}, trying a different port. Error: {:?}", address, e);
                     // Try binding on a new address if the original fails
-                    let alternative_address: Multiaddr = "/ip4/0.0.0.0/udp/0/quic-v1"
-                        .parse()
-                        .expect("Error creating new address");
+                    let alternative_address = Multiaddr::empty()
+                        .with(Protocol::Ip4(std::net::Ipv4Addr::UNSPECIFIED))
+                        .with(Protocol::Udp(0))
+                        .with(Protocol::QuicV1);
                     self.swarm
-                        .listen_on(alternative_address)
-                        .expect("Error listening on alternative address");
+                        .listen_on(alternative_address.clone())
+                        .map_err(|e| NetError::Listen {
+                            addr: alternative_address,
+                            reason: e.to_string(),
+                        })?;
                 }
             }
 
             SwarmEvent::ListenerClosed {
                 addresses, reason, ..
             } => {
+                for address in &addresses {
+                    self.emit(NetworkEvent::ListenAddrRemoved {
+                        address: address.clone(),
+                    });
+                }
                 warn!(
                     "Listener closed for addresses: {:?}, reason: {:?}",
                     addresses, reason
@@ -466,10 +1446,6 @@ impl EventLoop {
             } => {
                 warn!("Listener error on address {:?}: {:?}", listener_id, error);
             }
-            SwarmEvent::NewListenAddr { address, .. } => {
-                info!("Listening on new address: {:?}", address);
-            }
-
             _ => {
                 warn!("Did not match any specific event: {:?}", event);
             }
@@ -478,40 +1454,39 @@ impl EventLoop {
         Ok(())
     }
 
-    async fn handle_command(&mut self, command: Command) -> Result<(), anyhow::Error> {
+    async fn handle_command(&mut self, command: Command) -> Result<(), NetError> {
         match command {
-            Command::UploadFile { file_path, sender } => {
-                // Read the file as binary data
-                let file_data = fs::read(&file_path)
-                    .map_err(|e| anyhow!("Failed to read file from {:?}: {:?}", file_path, e))?;
-
-                // Create the file block
-                let block = FileBlock(file_data);
-
-                // Generate the CID
-                let cid = block
-                    .cid()
-                    .map_err(|e| anyhow!("Failed to generate CID: {:?}", e))?;
-
-                info!("Uploading file with CID: {}", cid);
-                self.blockstore
-                    .put_keyed(&cid, block.data())
-                    .await
-                    .map_err(|e| anyhow!("Failed to store block: {:?}", e))?;
-
+            Command::StartProviding { cid, sender } => {
                 let cid_key = RecordKey::new(&cid.to_bytes());
+                match self.swarm.behaviour_mut().kademlia.start_providing(cid_key) {
+                    Ok(query_id) => {
+                        self.pending_provides.insert(query_id, (cid, sender));
+                    }
+                    Err(e) => {
+                        let error = NetError::Provide {
+                            cid,
+                            reason: e.to_string(),
+                        };
+                        reply(sender, "start providing", Some(cid), Err(error));
+                    }
+                }
+            }
+            Command::StopProviding { cid, sender } => {
                 self.swarm
                     .behaviour_mut()
                     .kademlia
-                    .start_providing(cid_key)
-                    .map_err(|e| anyhow!("Failed to start providing the CID: {:?}", e))?;
-
-                // Send the CID as the result of the upload
-                sender
-                    .send(Ok(cid))
-                    .map_err(|e| anyhow!("Failed to send CID result: {:?}", e))?;
+                    .stop_providing(&RecordKey::new(&cid.to_bytes()));
+                reply(sender, "stop providing", Some(cid), Ok(()));
             }
             Command::RequestFile { cid, sender } => {
+                if let Some(query_id) = self.in_flight.get(&cid) {
+                    debug!("Joining the in-flight fetch of CID {}", cid);
+                    self.pending_requests
+                        .entry(*query_id)
+                        .or_default()
+                        .push(sender);
+                    return Ok(());
+                }
                 let query_id = self.swarm.behaviour_mut().bitswap.get(&cid);
                 let kad_query_id = self
                     .swarm
@@ -519,8 +1494,9 @@ impl EventLoop {
                     .kademlia
                     .get_providers(RecordKey::new(&cid.to_bytes()));
                 self.queries.insert(query_id, cid);
+                self.in_flight.insert(cid, query_id);
                 self.kad_queries.insert(kad_query_id, cid);
-                self.pending_requests.insert(query_id, sender);
+                self.pending_requests.insert(query_id, vec![sender]);
             }
             Command::StartListening { addr, sender } => {
                 let peer_id = *self.swarm.local_peer_id();
@@ -531,39 +1507,274 @@ impl EventLoop {
 
                 let result = self
                     .swarm
-                    .listen_on(addr)
+                    .listen_on(addr.clone())
                     .map(|_| peer_id.to_string())
-                    .map_err(|e| anyhow!("Failed to listen on address: {:?}", e));
-                sender
-                    .send(result)
-                    .map_err(|e| anyhow!("Failed to send start listening result: {:?}", e))?;
+                    .map_err(|e| NetError::Listen {
+                        addr,
+                        reason: e.to_string(),
+                    });
+                reply(sender, "start listening", None, result);
             }
 
             Command::GetProviders { cid, sender } => {
                 let query_id = self.swarm.behaviour_mut().kademlia.get_providers(cid);
-                self.pending_get_providers.insert(query_id, sender);
+                self.pending_get_providers.insert(
+                    query_id,
+                    ProviderSearch {
+                        sender,
+                        reported: HashSet::new(),
+                    },
+                );
                 info!("Searching for providers for CID, query ID: {:?}", query_id);
             }
-            Command::GetPeers { sender } => {
-                let peers: Vec<PeerId> = self.swarm.connected_peers().cloned().collect();
-                sender
-                    .send(Ok(peers))
-                    .map_err(|e| anyhow!("Failed to send peers: {:?}", e))?;
+            Command::GetStatus { sender } => {
+                let result = self.status();
+                reply(sender, "status", None, result);
+            }
+            Command::Shutdown { sender } => {
+                let result = self.shutdown().await;
+                if let Err(e) = &result {
+                    warn!("Error shutting down the network: {}", e);
+                }
+                reply(sender, "shutdown", None, result);
+            }
+        }
+
+        Ok(())
+    }
+
+    fn status(&mut self) -> Result<NodeStatus, NetError> {
+        let blockstore_bytes = self
+            .db
+            .size_on_disk()
+            .map_err(|e| NetError::Blockstore(e.to_string()))?;
+        let routing_table_size = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .map(|bucket| bucket.num_entries())
+            .sum();
+        Ok(NodeStatus {
+            peer_id: *self.swarm.local_peer_id(),
+            listen_addrs: self.swarm.listeners().cloned().collect(),
+            connected_peers: self.swarm.connected_peers().count(),
+            routing_table_size,
+            blockstore_bytes,
+            uptime: self.started_at.elapsed(),
+            ready: self.reached_network,
+        })
+    }
+
+    /// Publishes an event to the subscribers, if there are any.
+    fn emit(&self, event: NetworkEvent) {
+        let _ = self.event_sender.send(event);
+    }
+
+    /// Forwards newly found providers to a `find_providers` caller, stopping
+    /// the lookup once the caller is no longer listening.
+    fn report_providers(&mut self, id: kad::QueryId, providers: HashSet<PeerId>) {
+        let Some(mut search) = self.pending_get_providers.remove(&id) else {
+            return;
+        };
+
+        for peer_id in providers {
+            if !search.reported.insert(peer_id) {
+                continue;
+            }
+            let addresses = self.known_addresses(&peer_id);
+            if search
+                .sender
+                .unbounded_send(Provider { peer_id, addresses })
+                .is_err()
+            {
+                if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                    query.finish();
+                }
+                return;
+            }
+        }
+
+        self.pending_get_providers.insert(id, search);
+    }
+
+    /// Addresses the routing table (or our own listeners) know for `peer_id`.
+    fn known_addresses(&mut self, peer_id: &PeerId) -> Vec<Multiaddr> {
+        if peer_id == self.swarm.local_peer_id() {
+            return self.swarm.listeners().cloned().collect();
+        }
+        let Some(bucket) = self.swarm.behaviour_mut().kademlia.kbucket(*peer_id) else {
+            return Vec::new();
+        };
+        let addresses = bucket
+            .iter()
+            .find(|entry| entry.node.key.preimage() == peer_id)
+            .map(|entry| entry.node.value.iter().cloned().collect())
+            .unwrap_or_default();
+        addresses
+    }
+
+    /// Forgets a finished bitswap query, returning the CID it fetched.
+    fn finish_query(&mut self, query_id: beetswap::QueryId) -> Option<Cid> {
+        let cid = self.queries.remove(&query_id)?;
+        self.in_flight.remove(&cid);
+        Some(cid)
+    }
+
+    /// Drops callers that let go of the receiving end, either because the
+    /// fetch deadline passed or the requesting socket went away, and cancels
+    /// the queries nobody is waiting on anymore.
+    fn reap_cancelled_requests(&mut self) {
+        let mut cancelled = Vec::new();
+        for (query_id, senders) in self.pending_requests.iter_mut() {
+            senders.retain(|sender| !sender.is_canceled());
+            if senders.is_empty() {
+                cancelled.push(*query_id);
+            }
+        }
+
+        for query_id in cancelled {
+            self.cancel_request(query_id);
+        }
+    }
+
+    fn cancel_request(&mut self, query_id: beetswap::QueryId) {
+        self.pending_requests.remove(&query_id);
+        self.swarm.behaviour_mut().bitswap.cancel(query_id);
+
+        let Some(cid) = self.finish_query(query_id) else {
+            return;
+        };
+        info!("Cancelled request for CID {}", cid);
+
+        let kad_query_ids: Vec<kad::QueryId> = self
+            .kad_queries
+            .iter()
+            .filter(|(_, c)| **c == cid)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in kad_query_ids {
+            self.kad_queries.remove(&id);
+            if let Some(mut query) = self.swarm.behaviour_mut().kademlia.query_mut(&id) {
+                query.finish();
             }
         }
+    }
 
+    /// Saves the peers of the routing table, so the next start can contact
+    /// them right away.
+    fn save_routing_table(&mut self) {
+        let peers: Vec<(PeerId, Vec<Multiaddr>)> = self
+            .swarm
+            .behaviour_mut()
+            .kademlia
+            .kbuckets()
+            .flat_map(|bucket| {
+                bucket
+                    .iter()
+                    .map(|entry| {
+                        (
+                            *entry.node.key.preimage(),
+                            entry.node.value.iter().cloned().collect(),
+                        )
+                    })
+                    .collect::<Vec<_>>()
+            })
+            .collect();
+        let count = peers.len();
+        match RoutingSnapshot::open(&self.db).and_then(|routing| routing.save(peers)) {
+            Ok(()) => debug!("Saved {} peers of the routing table", count),
+            Err(e) => warn!("Failed to save the routing table: {}", e),
+        }
+    }
+
+    /// Fails the requests still waiting on the network, saves the routing
+    /// table and flushes the blockstore to disk. The node's own provider
+    /// records stay in the record store, so it goes on announcing its
+    /// content after a restart.
+    async fn shutdown(&mut self) -> Result<(), NetError> {
+        for (query_id, senders) in self.pending_requests.drain() {
+            self.swarm.behaviour_mut().bitswap.cancel(query_id);
+            let cid = self.queries.remove(&query_id);
+            for sender in senders {
+                reply(sender, "block", cid, Err(NetError::EventLoopStopped));
+            }
+        }
+        self.in_flight.clear();
+        self.pending_get_providers.clear();
+        for (_, (cid, sender)) in self.pending_provides.drain() {
+            reply(
+                sender,
+                "start providing",
+                Some(cid),
+                Err(NetError::EventLoopStopped),
+            );
+        }
+        self.save_routing_table();
+
+        self.db
+            .flush_async()
+            .await
+            .map_err(|e| NetError::Blockstore(e.to_string()))?;
+        info!("Blockstore flushed");
         Ok(())
     }
 
+    /// Runs the event loop on its own task, with a supervisor that marks the
+    /// client as no longer running when the loop exits for any reason,
+    /// including a panic. The returned handle completes after that.
+    pub fn spawn(self) -> JoinHandle<()> {
+        let running = self.running.clone();
+        let event_loop = tokio::spawn(self.run());
+        tokio::spawn(async move {
+            match event_loop.await {
+                Ok(()) => info!("Network event loop stopped"),
+                Err(e) => error!("Network event loop died: {}", e),
+            }
+            running.store(false, Ordering::SeqCst);
+        })
+    }
+
     pub async fn run(mut self) {
+        let mut cancel_sweep = tokio::time::interval(CANCEL_SWEEP_INTERVAL);
+        let mut routing_snapshot = tokio::time::interval(ROUTING_SNAPSHOT_INTERVAL);
+        // The first tick completes immediately, before any peer is known
+        routing_snapshot.reset();
         loop {
             select! {
-                event = self.swarm.select_next_some() => self.handle_event(event).await.expect("Error handling event"),
-                command = self.command_receiver.next() => match command {
-                    Some(c) => self.handle_command(c).await.expect("Error handling command"),
-                    None=>  return,
+                _ = cancel_sweep.tick() => self.reap_cancelled_requests(),
+                _ = routing_snapshot.tick() => self.save_routing_table(),
+                // A failure only affects the event or command at hand, the
+                // loop keeps serving everyone else.
+                event = self.swarm.select_next_some() => {
+                    if let Err(e) = self.handle_event(event).await {
+                        warn!("Error handling network event: {}", e);
+                    }
+                }
+                command = self.command_receiver.recv() => match command {
+                    Some(c) => {
+                        let stop = matches!(c, Command::Shutdown { .. });
+                        if let Err(e) = self.handle_command(c).await {
+                            warn!("Error handling command: {}", e);
+                        }
+                        if stop {
+                            return;
+                        }
+                    }
+                    None => return,
                 },
             }
         }
     }
 }
+
+/// Answers a command. Callers that stopped waiting, because their socket
+/// closed or their deadline passed, are expected and only logged.
+fn reply<T>(sender: oneshot::Sender<T>, what: &str, cid: Option<Cid>, value: T) {
+    if sender.send(value).is_err() {
+        match cid {
+            Some(cid) => debug!("Dropping {} reply for {}, the caller is gone", what, cid),
+            None => debug!("Dropping {} reply, the caller is gone", what),
+        }
+    }
+}
@@ -1,8 +1,12 @@
-use libp2p::identity;
+use anyhow::{anyhow, Result};
+use libp2p::{identity, PeerId};
 use serde::{Deserialize, Serialize};
-use std::fs::OpenOptions;
-use std::io::Write;
-use std::path::PathBuf;
+use std::fs::{self, OpenOptions};
+use std::io::{ErrorKind, Write};
+#[cfg(unix)]
+use std::os::unix::fs::{OpenOptionsExt, PermissionsExt};
+use std::path::{Path, PathBuf};
+use tracing::{info, warn};
 
 #[derive(Serialize, Deserialize, Clone)]
 pub enum NodeType {
@@ -23,38 +27,128 @@ pub struct PeerInfo {
     pub node_type: Option<NodeType>,
 }
 
-pub(crate) fn load_or_generate_keypair() -> identity::Keypair {
-    // let cache_path = cache_dir().unwrap();
-    // let mut file_path = PathBuf::from(cache_path);
-    // file_path.push("Boxpeer");
-    // file_path.push("peer_keypair.bin".to_string());
+/// Name of the file holding the node's protobuf-encoded keypair.
+const KEYPAIR_FILE: &str = "peer_keypair.bin";
 
-    // if let Ok(mut file) = File::open(&file_path) {
-    //     let mut contents = Vec::new();
-    //     let _ = file.read_to_end(&mut contents);
-    //     println!("In If");
-    //     identity::Keypair::from_protobuf_encoding(&contents).unwrap()
-    // } else {
+/// Loads the node identity from `data_dir`, generating and saving a new
+/// ed25519 keypair on first start so the PeerId survives restarts.
+pub(crate) fn load_or_generate_keypair(data_dir: &Path) -> Result<identity::Keypair> {
+    if let Some(keypair) = load_keypair(data_dir)? {
+        return Ok(keypair);
+    }
     // Generate a new keypair if no file is found
+    let file_path = data_dir.join(KEYPAIR_FILE);
     let keypair = identity::Keypair::generate_ed25519();
-    // println!("In else");
-    // // Save the keypair to disk
-    // let mut file = OpenOptions::new()
-    //     .write(true)
-    //     .create(true)
-    //     .open(&file_path)
-    //     .unwrap();
-    // let encoded = keypair.to_protobuf_encoding().unwrap();
-    // let _ = file.write_all(&encoded);
-    keypair
-    //}
-}
-
-pub async fn boxpeer_dir() -> Result<String, String> {
-            let mut dir = PathBuf::from("home/");
-            dir.push("Boxpeer");
-            dir.to_str()
-                .map(|s| s.to_string())
-                .ok_or("Failed to convert PathBuf to String".to_string())
+    write_keypair(&file_path, &keypair)?;
+    info!(
+        "Generated new identity {} in {}",
+        keypair.public().to_peer_id(),
+        file_path.display()
+    );
+    Ok(keypair)
+}
+
+/// The node identity saved in `data_dir`, if there is one.
+fn load_keypair(data_dir: &Path) -> Result<Option<identity::Keypair>> {
+    let file_path = data_dir.join(KEYPAIR_FILE);
+
+    match fs::read(&file_path) {
+        Ok(contents) => {
+            warn_if_readable_by_others(&file_path);
+            identity::Keypair::from_protobuf_encoding(&contents)
+                .map(Some)
+                .map_err(|e| {
+                    anyhow!(
+                        "Identity file {} is corrupt ({}); restore it with `identity import` or move it away to generate a new identity",
+                        file_path.display(),
+                        e
+                    )
+                })
+        }
+        Err(e) if e.kind() == ErrorKind::NotFound => Ok(None),
+        Err(e) => Err(anyhow!(
+            "Failed to read identity file {}: {}",
+            file_path.display(),
+            e
+        )),
+    }
+}
+
+/// Copies the node identity in `data_dir` to `destination`. Fails when the
+/// node has no identity yet rather than creating one.
+pub(crate) fn export_keypair(data_dir: &Path, destination: &Path) -> Result<PeerId> {
+    let keypair = load_keypair(data_dir)?
+        .ok_or_else(|| anyhow!("No identity found in {}", data_dir.display()))?;
+    write_keypair(destination, &keypair)?;
+    Ok(keypair.public().to_peer_id())
+}
+
+/// Replaces the node identity in `data_dir` with the keypair in `source`.
+pub(crate) fn import_keypair(data_dir: &Path, source: &Path) -> Result<PeerId> {
+    let contents = fs::read(source)
+        .map_err(|e| anyhow!("Failed to read identity file {}: {}", source.display(), e))?;
+    let keypair = identity::Keypair::from_protobuf_encoding(&contents)
+        .map_err(|e| anyhow!("{} is not a valid identity file: {}", source.display(), e))?;
+    fs::create_dir_all(data_dir)?;
+    write_keypair(&data_dir.join(KEYPAIR_FILE), &keypair)?;
+    Ok(keypair.public().to_peer_id())
+}
+
+/// Saves the keypair readable by the owner only. The key is written to a
+/// temporary file first so a crash never leaves a truncated identity behind.
+fn write_keypair(file_path: &Path, keypair: &identity::Keypair) -> Result<()> {
+    let encoded = keypair
+        .to_protobuf_encoding()
+        .map_err(|e| anyhow!("Failed to encode keypair: {}", e))?;
+    if let Some(parent) = file_path.parent() {
+        fs::create_dir_all(parent)?;
+    }
+
+    let tmp_path = file_path.with_extension("tmp");
+    let mut options = OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    options.mode(0o600);
+    let mut file = options
+        .open(&tmp_path)
+        .map_err(|e| anyhow!("Failed to create {}: {}", tmp_path.display(), e))?;
+    file.write_all(&encoded)?;
+    file.sync_all()?;
+    fs::rename(&tmp_path, file_path)
+        .map_err(|e| anyhow!("Failed to save identity to {}: {}", file_path.display(), e))?;
+    Ok(())
+}
+
+#[cfg(unix)]
+fn warn_if_readable_by_others(file_path: &Path) {
+    if let Ok(metadata) = fs::metadata(file_path) {
+        if metadata.permissions().mode() & 0o077 != 0 {
+            warn!(
+                "Identity file {} is accessible by other users, consider `chmod 600` on it",
+                file_path.display()
+            );
+        }
+    }
+}
+
+#[cfg(not(unix))]
+fn warn_if_readable_by_others(_file_path: &Path) {}
+
+/// Data directory of earlier releases, relative to the working directory.
+const LEGACY_DATA_DIR: &str = "home/Boxpeer";
 
+/// Where the blockstore and identity live when no data directory is
+/// configured. Nodes that already have data in the legacy location keep
+/// using it, new nodes follow the XDG base directory layout.
+pub fn default_data_dir() -> PathBuf {
+    let legacy = PathBuf::from(LEGACY_DATA_DIR);
+    if legacy.exists() {
+        return legacy;
+    }
+    std::env::var_os("XDG_DATA_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/share")))
+        .map(|base| base.join("boxpeer"))
+        .unwrap_or(legacy)
 }
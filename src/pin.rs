@@ -0,0 +1,251 @@
+use actix_web::{web, HttpRequest, HttpResponse};
+use cid::Cid;
+use serde::Serialize;
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::net::NetError;
+use crate::protocol::Announcement;
+use crate::upload;
+use crate::AppState;
+
+/// Name of the sled tree holding the pin set.
+const PINS_TREE: &str = "pins";
+
+/// Content the operator asked to keep. Pins are recursive: the whole DAG
+/// below the root stays in the blockstore.
+pub struct Pin {
+    pub cid: Cid,
+    pub pinned_at: SystemTime,
+}
+
+/// The pin set, persisted in the blockstore's database. Cloning is cheap and
+/// every clone sees the same set.
+#[derive(Clone)]
+pub struct PinStore {
+    tree: sled::Tree,
+}
+
+impl PinStore {
+    pub fn open(db: &sled::Db) -> Result<Self, NetError> {
+        Ok(Self {
+            tree: db.open_tree(PINS_TREE)?,
+        })
+    }
+
+    /// Records a pin, returning false when `cid` was already pinned.
+    pub fn insert(&self, cid: &Cid) -> Result<bool, NetError> {
+        let since_epoch = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        let previous = self.tree.compare_and_swap(
+            cid.to_bytes(),
+            None as Option<&[u8]>,
+            Some(&since_epoch.as_secs().to_be_bytes()[..]),
+        )?;
+        Ok(previous.is_ok())
+    }
+
+    /// Forgets a pin, returning false when `cid` was not pinned.
+    pub fn remove(&self, cid: &Cid) -> Result<bool, NetError> {
+        Ok(self.tree.remove(cid.to_bytes())?.is_some())
+    }
+
+    pub fn contains(&self, cid: &Cid) -> Result<bool, NetError> {
+        Ok(self.tree.contains_key(cid.to_bytes())?)
+    }
+
+    pub fn list(&self) -> Result<Vec<Pin>, NetError> {
+        self.tree
+            .iter()
+            .map(|entry| {
+                let (key, value) = entry?;
+                let cid = Cid::try_from(key.as_ref())
+                    .map_err(|e| NetError::Blockstore(format!("Invalid pinned CID: {}", e)))?;
+                let secs = value
+                    .as_ref()
+                    .try_into()
+                    .map(u64::from_be_bytes)
+                    .unwrap_or_default();
+                Ok(Pin {
+                    cid,
+                    pinned_at: UNIX_EPOCH + Duration::from_secs(secs),
+                })
+            })
+            .collect()
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub(crate) struct PinInfo {
+    pub cid: String,
+    /// Seconds since the Unix epoch.
+    pub pinned_at: u64,
+}
+
+impl From<Pin> for PinInfo {
+    fn from(pin: Pin) -> Self {
+        Self {
+            cid: pin.cid.to_string(),
+            pinned_at: pin
+                .pinned_at
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs(),
+        }
+    }
+}
+
+#[derive(Serialize)]
+struct PinsResponse {
+    count: usize,
+    pins: Vec<PinInfo>,
+}
+
+#[derive(Serialize)]
+struct PinResponse {
+    cid: String,
+    pinned: bool,
+    /// Only set on pins.
+    #[serde(flatten, skip_serializing_if = "Option::is_none")]
+    announcement: Option<Announcement>,
+}
+
+// Pin list route handler: GET /pins
+pub(crate) async fn get_pins(state: web::Data<AppState>) -> HttpResponse {
+    match state.client.list_pins() {
+        Ok(pins) => HttpResponse::Ok().json(PinsResponse {
+            count: pins.len(),
+            pins: pins.into_iter().map(PinInfo::from).collect(),
+        }),
+        Err(e) => HttpResponse::InternalServerError().body(format!("Error listing pins: {}", e)),
+    }
+}
+
+// Pin route handler: POST /pins/{cid}, fetches whatever is missing first
+pub(crate) async fn post_pin(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    if !upload::is_authorized(&state, upload::bearer_token(&req)) {
+        return HttpResponse::Unauthorized().body("A valid upload token is required");
+    }
+    let cid = match Cid::try_from(path.as_str()) {
+        Ok(cid) => cid,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid CID: {}", e)),
+    };
+
+    match state.client.pin(cid).await {
+        Ok(pinned) => HttpResponse::Ok().json(PinResponse {
+            cid: cid.to_string(),
+            pinned: true,
+            announcement: Some(Announcement::from(&pinned.announced)),
+        }),
+        Err(e) => HttpResponse::BadGateway().body(format!("Error pinning {}: {}", cid, e)),
+    }
+}
+
+// Unpin route handler: DELETE /pins/{cid}
+pub(crate) async fn delete_pin(
+    req: HttpRequest,
+    path: web::Path<String>,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    if !upload::is_authorized(&state, upload::bearer_token(&req)) {
+        return HttpResponse::Unauthorized().body("A valid upload token is required");
+    }
+    let cid = match Cid::try_from(path.as_str()) {
+        Ok(cid) => cid,
+        Err(e) => return HttpResponse::BadRequest().body(format!("Invalid CID: {}", e)),
+    };
+
+    match state.client.unpin(cid).await {
+        Ok(true) => HttpResponse::Ok().json(PinResponse {
+            cid: cid.to_string(),
+            pinned: false,
+            announcement: None,
+        }),
+        Ok(false) => HttpResponse::NotFound().body(format!("{} is not pinned", cid)),
+        Err(e) => {
+            HttpResponse::InternalServerError().body(format!("Error unpinning {}: {}", cid, e))
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::dag::{block_cid, RAW_CODEC};
+
+    fn cid(n: u8) -> Cid {
+        block_cid(RAW_CODEC, &[n])
+    }
+
+    fn temp_db() -> sled::Db {
+        sled::Config::new().temporary(true).open().unwrap()
+    }
+
+    fn pinned(pins: &PinStore) -> Vec<Cid> {
+        let mut cids: Vec<Cid> = pins
+            .list()
+            .unwrap()
+            .into_iter()
+            .map(|pin| pin.cid)
+            .collect();
+        cids.sort();
+        cids
+    }
+
+    #[test]
+    fn pins_and_unpins() {
+        let pins = PinStore::open(&temp_db()).unwrap();
+        assert!(pins.insert(&cid(1)).unwrap());
+        assert!(pins.insert(&cid(2)).unwrap());
+        assert!(pins.contains(&cid(1)).unwrap());
+        let mut expected = vec![cid(1), cid(2)];
+        expected.sort();
+        assert_eq!(pinned(&pins), expected);
+
+        assert!(pins.remove(&cid(1)).unwrap());
+        assert!(!pins.remove(&cid(1)).unwrap());
+        assert!(!pins.contains(&cid(1)).unwrap());
+        assert_eq!(pinned(&pins), vec![cid(2)]);
+    }
+
+    #[test]
+    fn repinning_keeps_the_first_pin() {
+        let pins = PinStore::open(&temp_db()).unwrap();
+        assert!(pins.insert(&cid(1)).unwrap());
+        let first = pins.list().unwrap()[0].pinned_at;
+        assert!(!pins.insert(&cid(1)).unwrap());
+        let list = pins.list().unwrap();
+        assert_eq!(list.len(), 1);
+        assert_eq!(list[0].pinned_at, first);
+    }
+
+    #[test]
+    fn pins_survive_reopening() {
+        let path = std::env::temp_dir().join(format!("boxpeer-pin-test-{}", std::process::id()));
+        let _ = std::fs::remove_dir_all(&path);
+        // Without a background flusher, which would keep the database
+        // locked for a moment after it is dropped
+        let open = || {
+            sled::Config::new()
+                .path(&path)
+                .flush_every_ms(None)
+                .open()
+                .unwrap()
+        };
+        {
+            let pins = PinStore::open(&open()).unwrap();
+            pins.clone().insert(&cid(1)).unwrap();
+            pins.insert(&cid(2)).unwrap();
+            pins.remove(&cid(2)).unwrap();
+        }
+        let pins = PinStore::open(&open()).unwrap();
+        assert_eq!(pinned(&pins), vec![cid(1)]);
+        assert!(!pins.contains(&cid(2)).unwrap());
+        drop(pins);
+        std::fs::remove_dir_all(&path).unwrap();
+    }
+}
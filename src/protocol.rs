@@ -0,0 +1,708 @@
+use cid::Cid;
+use serde::{Deserialize, Serialize};
+use std::collections::HashSet;
+
+use crate::net::{NetError, NetworkEvent, Provider};
+use crate::pin::PinInfo;
+use crate::upload::UploadResponse;
+
+/// Version of the JSON protocol spoken on `/ws`.
+pub const PROTOCOL_VERSION: u32 = 1;
+
+/// Prefix of the original plain-text command, kept as an alias of `GET_FILES`.
+pub const LEGACY_GET_FILES_PREFIX: &str = "GET_FILES:";
+
+/// Maximum payload carried by a single binary chunk frame.
+pub const FRAME_CHUNK_SIZE: usize = 64 * 1024;
+
+/// Version byte leading every binary chunk frame.
+pub const FRAME_VERSION: u8 = 2;
+
+const FRAME_FLAG_FINAL: u8 = 0b0000_0001;
+
+/// Client supplied identifier echoed back on every reply to a request.
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq, Eq, Hash)]
+#[serde(untagged)]
+pub enum RequestId {
+    Number(u64),
+    Text(String),
+}
+
+#[derive(Deserialize, Debug)]
+pub struct ClientRequest {
+    #[serde(default = "default_version")]
+    pub v: u32,
+    #[serde(default)]
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub command: ClientCommand,
+}
+
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ClientCommand {
+    /// Fetches files; `revalidate` checks cached blocks against their CID
+    /// and refetches the ones that do not match.
+    GetFiles {
+        cids: Vec<String>,
+        #[serde(default)]
+        revalidate: bool,
+    },
+    /// Streams the peers providing `cid` as the DHT lookup finds them.
+    FindProviders {
+        cid: String,
+    },
+    /// Aborts the in-flight request whose id is `target`.
+    Cancel {
+        target: RequestId,
+    },
+    /// Starts an upload; the file content follows as binary frames.
+    Upload {
+        #[serde(default)]
+        token: Option<String>,
+    },
+    /// Ends the upload started by `UPLOAD` and stores the file.
+    UploadFinish,
+    /// Streams network events until cancelled with `CANCEL`.
+    Subscribe,
+    /// Keeps `cid` and its whole DAG, fetching what is missing.
+    Pin {
+        cid: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    Unpin {
+        cid: String,
+        #[serde(default)]
+        token: Option<String>,
+    },
+    ListPins,
+}
+
+#[derive(Serialize, Debug)]
+pub struct ServerResponse {
+    pub v: u32,
+    pub id: Option<RequestId>,
+    #[serde(flatten)]
+    pub message: ServerMessage,
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "type", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum ServerMessage {
+    /// Announces a file whose content follows as binary chunk frames
+    /// tagged with `transfer`.
+    File {
+        cid: String,
+        size: u64,
+        chunks: u32,
+        transfer: u32,
+    },
+    Done {
+        fetched: usize,
+        failed: usize,
+    },
+    Provider {
+        cid: String,
+        #[serde(flatten)]
+        provider: ProviderInfo,
+    },
+    /// The provider lookup for `cid` has finished.
+    ProvidersDone {
+        cid: String,
+        count: usize,
+    },
+    /// Sent with the id of a request that was aborted by `CANCEL`.
+    Cancelled,
+    /// The server accepts binary frames for the upload.
+    UploadReady,
+    Uploaded(UploadResponse),
+    /// A network event for a `SUBSCRIBE` request.
+    Event {
+        #[serde(flatten)]
+        event: EventInfo,
+    },
+    /// The subscriber fell behind and `count` events were skipped.
+    EventsMissed {
+        count: u64,
+    },
+    Pinned {
+        cid: String,
+        #[serde(flatten)]
+        announcement: Announcement,
+    },
+    Unpinned {
+        cid: String,
+    },
+    Pins {
+        pins: Vec<PinInfo>,
+    },
+    Error {
+        code: ErrorCode,
+        message: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        cid: Option<String>,
+        /// Set when a file transfer failed; frames already sent for it are
+        /// incomplete.
+        #[serde(skip_serializing_if = "Option::is_none")]
+        transfer: Option<u32>,
+    },
+}
+
+/// Whether stored content was announced on the DHT, reported next to
+/// uploads and pins since those succeed either way.
+#[derive(Serialize, Debug)]
+pub struct Announcement {
+    pub announced: bool,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub announce_error: Option<String>,
+}
+
+impl From<&Result<(), NetError>> for Announcement {
+    fn from(result: &Result<(), NetError>) -> Self {
+        Self {
+            announced: result.is_ok(),
+            announce_error: result.as_ref().err().map(|e| e.to_string()),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+pub struct ProviderInfo {
+    pub peer_id: String,
+    pub addresses: Vec<String>,
+}
+
+impl From<Provider> for ProviderInfo {
+    fn from(provider: Provider) -> Self {
+        Self {
+            peer_id: provider.peer_id.to_string(),
+            addresses: provider.addresses.iter().map(|a| a.to_string()).collect(),
+        }
+    }
+}
+
+#[derive(Serialize, Debug)]
+#[serde(tag = "event", rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum EventInfo {
+    PeerConnected {
+        peer_id: String,
+    },
+    PeerDisconnected {
+        peer_id: String,
+    },
+    ProviderFound {
+        cid: String,
+        peer_id: String,
+    },
+    BlockReceived {
+        cid: String,
+        size: usize,
+    },
+    RoutingUpdated {
+        peer_id: String,
+        addresses: Vec<String>,
+    },
+    ListenAddrAdded {
+        address: String,
+    },
+    ListenAddrRemoved {
+        address: String,
+    },
+}
+
+impl From<NetworkEvent> for EventInfo {
+    fn from(event: NetworkEvent) -> Self {
+        match event {
+            NetworkEvent::PeerConnected { peer_id } => EventInfo::PeerConnected {
+                peer_id: peer_id.to_string(),
+            },
+            NetworkEvent::PeerDisconnected { peer_id } => EventInfo::PeerDisconnected {
+                peer_id: peer_id.to_string(),
+            },
+            NetworkEvent::ProviderFound { cid, peer_id } => EventInfo::ProviderFound {
+                cid: cid.to_string(),
+                peer_id: peer_id.to_string(),
+            },
+            NetworkEvent::BlockReceived { cid, size } => EventInfo::BlockReceived {
+                cid: cid.to_string(),
+                size,
+            },
+            NetworkEvent::RoutingUpdated { peer_id, addresses } => EventInfo::RoutingUpdated {
+                peer_id: peer_id.to_string(),
+                addresses: addresses.iter().map(|a| a.to_string()).collect(),
+            },
+            NetworkEvent::ListenAddrAdded { address } => EventInfo::ListenAddrAdded {
+                address: address.to_string(),
+            },
+            NetworkEvent::ListenAddrRemoved { address } => EventInfo::ListenAddrRemoved {
+                address: address.to_string(),
+            },
+        }
+    }
+}
+
+#[derive(Serialize, Clone, Copy, Debug, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    /// The frame was not valid JSON or did not match any known command.
+    BadRequest,
+    /// The `v` field names a protocol version this server does not speak.
+    UnsupportedVersion,
+    /// A CID in the request could not be parsed.
+    InvalidCid,
+    /// The request did not contain a single usable CID.
+    NoValidCids,
+    /// The network failed to deliver the requested content.
+    FetchFailed,
+    /// No peer delivered the content before the fetch deadline.
+    Timeout,
+    /// The DHT lookup could not be started.
+    LookupFailed,
+    /// `CANCEL` named a request that is not in flight.
+    UnknownRequest,
+    /// A request reused the id of a request that is still in flight.
+    DuplicateRequest,
+    /// The upload token is missing or wrong, or uploads are disabled.
+    Unauthorized,
+    /// `UPLOAD` was sent while another upload is still open.
+    UploadInProgress,
+    /// Upload data or `UPLOAD_FINISH` arrived without an open upload.
+    NoUpload,
+    /// The uploaded content could not be stored.
+    UploadFailed,
+    /// The upload went past the largest file the server accepts.
+    UploadTooLarge,
+    /// The network event loop has stopped, no request can be served.
+    Unavailable,
+    /// The server is shutting down and accepts no new requests.
+    ShuttingDown,
+    /// The content could not be fetched or the pin not recorded.
+    PinFailed,
+    /// `UNPIN` named a CID that is not pinned.
+    NotPinned,
+}
+
+fn default_version() -> u32 {
+    PROTOCOL_VERSION
+}
+
+impl ServerResponse {
+    pub fn new(id: Option<RequestId>, message: ServerMessage) -> Self {
+        Self {
+            v: PROTOCOL_VERSION,
+            id,
+            message,
+        }
+    }
+
+    pub fn error(id: Option<RequestId>, code: ErrorCode, message: impl Into<String>) -> Self {
+        Self::new(
+            id,
+            ServerMessage::Error {
+                code,
+                message: message.into(),
+                cid: None,
+                transfer: None,
+            },
+        )
+    }
+
+    pub fn cid_error(
+        id: Option<RequestId>,
+        code: ErrorCode,
+        cid: impl ToString,
+        message: impl Into<String>,
+    ) -> Self {
+        Self::new(
+            id,
+            ServerMessage::Error {
+                code,
+                message: message.into(),
+                cid: Some(cid.to_string()),
+                transfer: None,
+            },
+        )
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).expect("Server responses always serialize")
+    }
+}
+
+/// Parses a text frame into a request, accepting both the JSON protocol and
+/// the legacy `GET_FILES:cid,cid` form. Errors are returned as ready-to-send
+/// responses.
+pub fn parse_request(text: &str) -> Result<ClientRequest, ServerResponse> {
+    if let Some(cid_strs) = text.strip_prefix(LEGACY_GET_FILES_PREFIX) {
+        return Ok(ClientRequest {
+            v: PROTOCOL_VERSION,
+            id: None,
+            command: ClientCommand::GetFiles {
+                cids: cid_strs.split(',').map(|s| s.trim().to_string()).collect(),
+                revalidate: false,
+            },
+        });
+    }
+
+    let request: ClientRequest = serde_json::from_str(text).map_err(|e| {
+        ServerResponse::error(
+            None,
+            ErrorCode::BadRequest,
+            format!("Invalid request: {}", e),
+        )
+    })?;
+
+    if request.v != PROTOCOL_VERSION {
+        return Err(ServerResponse::error(
+            request.id,
+            ErrorCode::UnsupportedVersion,
+            format!(
+                "Unsupported protocol version {}, expected {}",
+                request.v, PROTOCOL_VERSION
+            ),
+        ));
+    }
+
+    Ok(request)
+}
+
+/// Splits CID strings into the parsed CIDs and the strings that failed to
+/// parse. A CID listed more than once is only returned the first time.
+pub fn parse_cids(cid_strs: &[String]) -> (Vec<Cid>, Vec<String>) {
+    let mut cids = Vec::new();
+    let mut seen = HashSet::new();
+    let mut invalid = Vec::new();
+    for s in cid_strs.iter().map(|s| s.trim()).filter(|s| !s.is_empty()) {
+        match Cid::try_from(s) {
+            Ok(cid) => {
+                if seen.insert(cid) {
+                    cids.push(cid);
+                }
+            }
+            Err(_) => invalid.push(s.to_string()),
+        }
+    }
+    (cids, invalid)
+}
+
+/// One binary WebSocket frame carrying part of a file.
+///
+/// Layout, all integers big-endian:
+///
+/// ```text
+/// u8  version | u8 flags (bit 0 = final) | u32 transfer id
+/// u16 cid length | cid bytes | u32 chunk index | u64 total file size | payload
+/// ```
+///
+/// The transfer id comes from the `FILE` reply announcing the file, so
+/// transfers of the same CID running at once stay apart.
+pub struct ChunkFrame<'a> {
+    pub transfer: u32,
+    pub cid: &'a Cid,
+    pub index: u32,
+    pub total_size: u64,
+    pub is_final: bool,
+    pub payload: &'a [u8],
+}
+
+impl ChunkFrame<'_> {
+    pub fn encode(&self) -> Vec<u8> {
+        let cid = self.cid.to_bytes();
+        let mut frame = Vec::with_capacity(20 + cid.len() + self.payload.len());
+        frame.push(FRAME_VERSION);
+        frame.push(if self.is_final { FRAME_FLAG_FINAL } else { 0 });
+        frame.extend_from_slice(&self.transfer.to_be_bytes());
+        frame.extend_from_slice(&(cid.len() as u16).to_be_bytes());
+        frame.extend_from_slice(&cid);
+        frame.extend_from_slice(&self.index.to_be_bytes());
+        frame.extend_from_slice(&self.total_size.to_be_bytes());
+        frame.extend_from_slice(self.payload);
+        frame
+    }
+}
+
+/// Number of frames a file of `size` bytes is sent in.
+pub fn chunk_count(size: u64) -> u32 {
+    size.div_ceil(FRAME_CHUNK_SIZE as u64).max(1) as u32
+}
+
+/// Cuts file content arriving in parts of any size into chunk frames of
+/// `FRAME_CHUNK_SIZE`, holding back at most one frame of payload. Empty
+/// files still yield a single, final frame so the client sees the transfer
+/// complete.
+pub struct FrameWriter {
+    transfer: u32,
+    cid: Cid,
+    total_size: u64,
+    count: u32,
+    index: u32,
+    pending: Vec<u8>,
+}
+
+impl FrameWriter {
+    pub fn new(transfer: u32, cid: Cid, total_size: u64) -> Self {
+        Self {
+            transfer,
+            cid,
+            total_size,
+            count: chunk_count(total_size),
+            index: 0,
+            pending: Vec::with_capacity(FRAME_CHUNK_SIZE),
+        }
+    }
+
+    /// Adds content, returning the frames it completed.
+    pub fn push(&mut self, mut data: &[u8]) -> Vec<Vec<u8>> {
+        let mut frames = Vec::new();
+        while !data.is_empty() {
+            let take = (FRAME_CHUNK_SIZE - self.pending.len()).min(data.len());
+            self.pending.extend_from_slice(&data[..take]);
+            data = &data[take..];
+            if self.pending.len() == FRAME_CHUNK_SIZE {
+                frames.push(self.frame());
+            }
+        }
+        frames
+    }
+
+    /// Returns the last frame, unless the content ended on a frame
+    /// boundary and it was already sent.
+    pub fn finish(mut self) -> Option<Vec<u8>> {
+        (!self.pending.is_empty() || self.index == 0).then(|| self.frame())
+    }
+
+    fn frame(&mut self) -> Vec<u8> {
+        let frame = ChunkFrame {
+            transfer: self.transfer,
+            cid: &self.cid,
+            index: self.index,
+            total_size: self.total_size,
+            is_final: self.index + 1 == self.count,
+            payload: &self.pending,
+        }
+        .encode();
+        self.index += 1;
+        self.pending.clear();
+        frame
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use multihash_codetable::{Code, MultihashDigest};
+
+    fn cid(data: &[u8]) -> Cid {
+        Cid::new_v1(0x55, Code::Sha2_256.digest(data))
+    }
+
+    struct Decoded {
+        is_final: bool,
+        transfer: u32,
+        cid: Cid,
+        index: u32,
+        total_size: u64,
+        payload: Vec<u8>,
+    }
+
+    fn decode_frame(frame: &[u8]) -> Decoded {
+        assert_eq!(frame[0], FRAME_VERSION);
+        let cid_len = u16::from_be_bytes([frame[6], frame[7]]) as usize;
+        let (cid, rest) = frame[8..].split_at(cid_len);
+        Decoded {
+            is_final: frame[1] & FRAME_FLAG_FINAL != 0,
+            transfer: u32::from_be_bytes(frame[2..6].try_into().unwrap()),
+            cid: Cid::try_from(cid).unwrap(),
+            index: u32::from_be_bytes(rest[..4].try_into().unwrap()),
+            total_size: u64::from_be_bytes(rest[4..12].try_into().unwrap()),
+            payload: rest[12..].to_vec(),
+        }
+    }
+
+    /// Feeds `data` to a writer in parts of `part` bytes.
+    fn write_frames(data: &[u8], part: usize) -> Vec<Decoded> {
+        let cid = cid(data);
+        let mut writer = FrameWriter::new(3, cid, data.len() as u64);
+        let mut frames = Vec::new();
+        for piece in data.chunks(part) {
+            frames.extend(writer.push(piece));
+        }
+        frames.extend(writer.finish());
+        frames.iter().map(|frame| decode_frame(frame)).collect()
+    }
+
+    fn check_frames(data: &[u8], frames: &[Decoded]) {
+        let cid = cid(data);
+        assert_eq!(frames.len(), chunk_count(data.len() as u64) as usize);
+        for (i, frame) in frames.iter().enumerate() {
+            assert_eq!(frame.transfer, 3);
+            assert_eq!(frame.cid, cid);
+            assert_eq!(frame.index, i as u32);
+            assert_eq!(frame.total_size, data.len() as u64);
+            assert_eq!(frame.is_final, i + 1 == frames.len());
+            assert!(frame.payload.len() <= FRAME_CHUNK_SIZE);
+        }
+        let payload: Vec<u8> = frames.iter().flat_map(|f| f.payload.clone()).collect();
+        assert_eq!(payload, data);
+    }
+
+    #[test]
+    fn frame_layout() {
+        let cid = cid(b"abc");
+        let frame = ChunkFrame {
+            transfer: 0x0102_0304,
+            cid: &cid,
+            index: 7,
+            total_size: 3,
+            is_final: true,
+            payload: b"abc",
+        }
+        .encode();
+        let cid_len = cid.to_bytes().len();
+        assert_eq!(frame.len(), 20 + cid_len + 3);
+        assert_eq!(&frame[..6], &[FRAME_VERSION, FRAME_FLAG_FINAL, 1, 2, 3, 4]);
+        let decoded = decode_frame(&frame);
+        assert_eq!(decoded.transfer, 0x0102_0304);
+        assert_eq!(decoded.cid, cid);
+        assert_eq!(decoded.index, 7);
+        assert_eq!(decoded.total_size, 3);
+        assert_eq!(decoded.payload, b"abc");
+    }
+
+    #[test]
+    fn empty_file_is_one_final_frame() {
+        let frames = write_frames(&[], 1);
+        assert_eq!(frames.len(), 1);
+        assert!(frames[0].is_final);
+        assert!(frames[0].payload.is_empty());
+    }
+
+    #[test]
+    fn file_smaller_than_a_frame() {
+        let data = vec![1; 1000];
+        check_frames(&data, &write_frames(&data, 100));
+    }
+
+    #[test]
+    fn file_on_frame_boundary() {
+        let data: Vec<u8> = (0..FRAME_CHUNK_SIZE * 3).map(|i| i as u8).collect();
+        let frames = write_frames(&data, 10_000);
+        assert_eq!(frames.len(), 3);
+        check_frames(&data, &frames);
+    }
+
+    #[test]
+    fn parts_larger_than_a_frame() {
+        let data: Vec<u8> = (0..FRAME_CHUNK_SIZE * 2 + 123).map(|i| i as u8).collect();
+        check_frames(&data, &write_frames(&data, FRAME_CHUNK_SIZE * 2));
+        check_frames(&data, &write_frames(&data, data.len()));
+    }
+
+    #[test]
+    fn chunk_counts() {
+        let frame = FRAME_CHUNK_SIZE as u64;
+        assert_eq!(chunk_count(0), 1);
+        assert_eq!(chunk_count(1), 1);
+        assert_eq!(chunk_count(frame), 1);
+        assert_eq!(chunk_count(frame + 1), 2);
+    }
+
+    fn parse_error(text: &str) -> (Option<RequestId>, ErrorCode) {
+        match parse_request(text) {
+            Ok(request) => panic!("{:?} parsed", request),
+            Err(ServerResponse {
+                id,
+                message: ServerMessage::Error { code, .. },
+                ..
+            }) => (id, code),
+            Err(response) => panic!("not an error: {}", response.to_json()),
+        }
+    }
+
+    #[test]
+    fn parses_json_command() {
+        let request =
+            parse_request(r#"{"v":1,"id":3,"type":"GET_FILES","cids":["a","b"]}"#).unwrap();
+        assert_eq!(request.id, Some(RequestId::Number(3)));
+        match request.command {
+            ClientCommand::GetFiles { cids, revalidate } => {
+                assert_eq!(cids, ["a", "b"]);
+                assert!(!revalidate);
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn version_and_id_are_optional() {
+        let request = parse_request(r#"{"type":"LIST_PINS"}"#).unwrap();
+        assert_eq!(request.v, PROTOCOL_VERSION);
+        assert_eq!(request.id, None);
+        assert!(matches!(request.command, ClientCommand::ListPins));
+    }
+
+    #[test]
+    fn parses_text_ids_and_cancel() {
+        let request = parse_request(r#"{"id":"x","type":"CANCEL","target":"y"}"#).unwrap();
+        assert_eq!(request.id, Some(RequestId::Text("x".into())));
+        assert!(matches!(
+            request.command,
+            ClientCommand::Cancel { target: RequestId::Text(target) } if target == "y"
+        ));
+    }
+
+    #[test]
+    fn parses_legacy_get_files() {
+        let request = parse_request("GET_FILES: a , b").unwrap();
+        assert_eq!(request.v, PROTOCOL_VERSION);
+        assert_eq!(request.id, None);
+        match request.command {
+            ClientCommand::GetFiles { cids, revalidate } => {
+                assert_eq!(cids, ["a", "b"]);
+                assert!(!revalidate);
+            }
+            command => panic!("parsed as {:?}", command),
+        }
+    }
+
+    #[test]
+    fn rejects_other_versions() {
+        assert_eq!(
+            parse_error(r#"{"v":2,"id":5,"type":"LIST_PINS"}"#),
+            (Some(RequestId::Number(5)), ErrorCode::UnsupportedVersion)
+        );
+    }
+
+    #[test]
+    fn rejects_bad_json() {
+        assert_eq!(parse_error("{"), (None, ErrorCode::BadRequest));
+        assert_eq!(parse_error("hello"), (None, ErrorCode::BadRequest));
+        assert_eq!(
+            parse_error(r#"{"type":"NOPE"}"#),
+            (None, ErrorCode::BadRequest)
+        );
+        assert_eq!(
+            parse_error(r#"{"type":"GET_FILES"}"#),
+            (None, ErrorCode::BadRequest)
+        );
+    }
+
+    #[test]
+    fn splits_valid_and_invalid_cids() {
+        let cid = cid(b"x").to_string();
+        let (cids, invalid) = parse_cids(&[cid.clone(), " ".into(), "bad".into()]);
+        assert_eq!(cids.len(), 1);
+        assert_eq!(cids[0].to_string(), cid);
+        assert_eq!(invalid, ["bad"]);
+    }
+
+    #[test]
+    fn drops_repeated_cids() {
+        let first = cid(b"x").to_string();
+        let second = cid(b"y").to_string();
+        let (cids, _) = parse_cids(&[first.clone(), second.clone(), format!(" {}", first)]);
+        let cids: Vec<String> = cids.iter().map(Cid::to_string).collect();
+        assert_eq!(cids, [first, second]);
+    }
+}
@@ -0,0 +1,87 @@
+use actix_web::{web, HttpResponse};
+use serde::Serialize;
+
+use crate::cache::CacheStats;
+use crate::net::NodeStatus;
+use crate::AppState;
+
+#[derive(Serialize)]
+struct StatusResponse {
+    peer_id: String,
+    listen_addrs: Vec<String>,
+    connected_peers: usize,
+    routing_table_size: usize,
+    blockstore_bytes: u64,
+    uptime_secs: u64,
+    ready: bool,
+    cache: CacheResponse,
+}
+
+#[derive(Serialize)]
+struct CacheResponse {
+    capacity_bytes: usize,
+    bytes: usize,
+    blocks: usize,
+    hits: u64,
+    misses: u64,
+    hit_rate: f64,
+}
+
+impl From<CacheStats> for CacheResponse {
+    fn from(stats: CacheStats) -> Self {
+        Self {
+            capacity_bytes: stats.capacity,
+            bytes: stats.bytes,
+            blocks: stats.blocks,
+            hits: stats.hits,
+            misses: stats.misses,
+            hit_rate: stats.hit_rate(),
+        }
+    }
+}
+
+impl StatusResponse {
+    fn new(status: NodeStatus, cache: CacheStats) -> Self {
+        Self {
+            peer_id: status.peer_id.to_string(),
+            listen_addrs: status.listen_addrs.iter().map(|a| a.to_string()).collect(),
+            connected_peers: status.connected_peers,
+            routing_table_size: status.routing_table_size,
+            blockstore_bytes: status.blockstore_bytes,
+            uptime_secs: status.uptime.as_secs(),
+            ready: status.ready,
+            cache: cache.into(),
+        }
+    }
+}
+
+// Liveness route handler: GET /healthz, healthy while the network event loop runs
+pub(crate) async fn get_healthz(state: web::Data<AppState>) -> HttpResponse {
+    if state.client.is_running() {
+        HttpResponse::Ok().body("ok")
+    } else {
+        HttpResponse::ServiceUnavailable().body("network event loop stopped")
+    }
+}
+
+// Readiness route handler: GET /readyz, ready once the node has reached the network
+pub(crate) async fn get_readyz(state: web::Data<AppState>) -> HttpResponse {
+    if *state.shutdown.borrow() {
+        return HttpResponse::ServiceUnavailable().body("shutting down");
+    }
+    match state.client.status().await {
+        Ok(status) if status.ready => HttpResponse::Ok().body("ready"),
+        Ok(_) => HttpResponse::ServiceUnavailable().body("no peer reached yet"),
+        Err(e) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+    }
+}
+
+// Node status route handler: GET /status
+pub(crate) async fn get_status(state: web::Data<AppState>) -> HttpResponse {
+    match state.client.status().await {
+        Ok(status) => {
+            HttpResponse::Ok().json(StatusResponse::new(status, state.client.cache_stats()))
+        }
+        Err(e) => HttpResponse::ServiceUnavailable().body(e.to_string()),
+    }
+}
@@ -0,0 +1,205 @@
+use actix_multipart::Multipart;
+use actix_web::http::header;
+use actix_web::{web, HttpRequest, HttpResponse};
+use futures::{Stream, StreamExt};
+use serde::Serialize;
+
+use crate::net::{NetError, UploadedFile};
+use crate::protocol::Announcement;
+use crate::AppState;
+
+/// Bytes from the start of a file kept for content type detection.
+pub(crate) const SNIFF_LEN: usize = 512;
+
+pub(crate) const OCTET_STREAM: &str = "application/octet-stream";
+
+#[derive(Serialize, Debug)]
+pub(crate) struct UploadResponse {
+    pub cid: String,
+    pub size: u64,
+    pub content_type: String,
+    #[serde(flatten)]
+    pub announcement: Announcement,
+}
+
+impl UploadResponse {
+    pub(crate) fn new(uploaded: UploadedFile, head: &[u8], declared: Option<&str>) -> Self {
+        Self {
+            cid: uploaded.cid.to_string(),
+            size: uploaded.size,
+            content_type: detect_content_type(head, declared).to_string(),
+            announcement: Announcement::from(&uploaded.announced),
+        }
+    }
+}
+
+// Upload route handler: POST /upload, either multipart/form-data or a raw body
+pub(crate) async fn post_upload(
+    req: HttpRequest,
+    payload: web::Payload,
+    state: web::Data<AppState>,
+) -> HttpResponse {
+    if !is_authorized(&state, bearer_token(&req)) {
+        return HttpResponse::Unauthorized().body("A valid upload token is required");
+    }
+
+    // Bodies announcing more than the limit are refused before any of them
+    // is read; the limit is enforced while importing for the rest
+    let limit = state.client.max_upload_size();
+    let length = req
+        .headers()
+        .get(header::CONTENT_LENGTH)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.parse::<u64>().ok());
+    if length.is_some_and(|length| length > limit) {
+        return HttpResponse::PayloadTooLarge()
+            .body(NetError::UploadTooLarge { limit }.to_string());
+    }
+
+    let declared = req
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .and_then(|value| value.to_str().ok())
+        .map(|value| value.to_string());
+
+    let result = match declared.as_deref() {
+        Some(content_type) if content_type.starts_with("multipart/form-data") => {
+            let mut multipart = Multipart::new(req.headers(), payload);
+            // The first field carrying a file is the upload, other form
+            // fields are ignored.
+            loop {
+                match multipart.next().await {
+                    Some(Ok(field)) => {
+                        let is_file = field
+                            .content_disposition()
+                            .is_some_and(|cd| cd.get_filename().is_some());
+                        if !is_file {
+                            continue;
+                        }
+                        let declared = field.content_type().map(|mime| mime.to_string());
+                        // Multipart errors are not `Send`, so flatten them first
+                        let field = field.map(|chunk| {
+                            chunk.map_err(|e| anyhow::anyhow!("Invalid multipart body: {}", e))
+                        });
+                        break upload(&state, field, declared.as_deref()).await;
+                    }
+                    Some(Err(e)) => break Err(anyhow::anyhow!("Invalid multipart body: {}", e)),
+                    None => {
+                        return HttpResponse::BadRequest().body("No file field in multipart body")
+                    }
+                }
+            }
+        }
+        declared => upload(&state, payload, declared).await,
+    };
+
+    match result {
+        Ok(response) => HttpResponse::Ok().json(response),
+        Err(e) => match e.downcast_ref::<NetError>() {
+            Some(NetError::UploadTooLarge { .. }) => {
+                HttpResponse::PayloadTooLarge().body(e.to_string())
+            }
+            _ => HttpResponse::InternalServerError().body(format!("Upload failed: {}", e)),
+        },
+    }
+}
+
+async fn upload<S, B, E>(
+    state: &AppState,
+    stream: S,
+    declared: Option<&str>,
+) -> anyhow::Result<UploadResponse>
+where
+    S: Stream<Item = Result<B, E>>,
+    B: AsRef<[u8]>,
+    E: Into<anyhow::Error>,
+{
+    let mut head = Vec::new();
+    let uploaded = state
+        .client
+        .upload_stream(stream.inspect(|chunk| {
+            if let Ok(chunk) = chunk {
+                capture_head(&mut head, chunk.as_ref());
+            }
+        }))
+        .await?;
+    Ok(UploadResponse::new(uploaded, &head, declared))
+}
+
+/// The token of an `Authorization: Bearer` header.
+pub(crate) fn bearer_token(req: &HttpRequest) -> Option<&str> {
+    req.headers()
+        .get(header::AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix("Bearer "))
+}
+
+/// Uploads are only accepted once an upload token is configured, and only
+/// from callers presenting it.
+pub(crate) fn is_authorized(state: &AppState, token: Option<&str>) -> bool {
+    match (&state.upload_token, token) {
+        (Some(expected), Some(token)) => constant_time_eq(expected.as_bytes(), token.as_bytes()),
+        _ => false,
+    }
+}
+
+fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |diff, (x, y)| diff | (x ^ y)) == 0
+}
+
+/// Keeps the first `SNIFF_LEN` bytes of an upload for `detect_content_type`.
+pub(crate) fn capture_head(head: &mut Vec<u8>, chunk: &[u8]) {
+    let wanted = SNIFF_LEN.saturating_sub(head.len()).min(chunk.len());
+    head.extend_from_slice(&chunk[..wanted]);
+}
+
+/// Detects a content type from the leading bytes of a file, falling back to
+/// what the uploader declared. Form encodings say nothing about the file and
+/// are what curl and browsers send by default, so they are ignored.
+pub(crate) fn detect_content_type<'a>(head: &[u8], declared: Option<&'a str>) -> &'a str {
+    if let Some(sniffed) = sniff_content_type(head) {
+        return sniffed;
+    }
+    match declared {
+        Some(declared)
+            if !declared.is_empty()
+                && !declared.starts_with("multipart/")
+                && !declared.starts_with("application/x-www-form-urlencoded") =>
+        {
+            declared
+        }
+        _ => OCTET_STREAM,
+    }
+}
+
+/// Recognizes the media formats that make up most of the catalogue by their
+/// magic numbers.
+pub(crate) fn sniff_content_type(head: &[u8]) -> Option<&'static str> {
+    const SIGNATURES: &[(&[u8], &str)] = &[
+        (b"\x89PNG\r\n\x1a\n", "image/png"),
+        (b"\xff\xd8\xff", "image/jpeg"),
+        (b"GIF87a", "image/gif"),
+        (b"GIF89a", "image/gif"),
+        (b"%PDF-", "application/pdf"),
+        (b"\x1a\x45\xdf\xa3", "video/webm"),
+        (b"OggS", "audio/ogg"),
+        (b"fLaC", "audio/flac"),
+        (b"ID3", "audio/mpeg"),
+        (b"PK\x03\x04", "application/zip"),
+    ];
+    if let Some((_, content_type)) = SIGNATURES.iter().find(|(magic, _)| head.starts_with(magic)) {
+        return Some(content_type);
+    }
+
+    match head {
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'E', b'B', b'P', ..] => Some("image/webp"),
+        [b'R', b'I', b'F', b'F', _, _, _, _, b'W', b'A', b'V', b'E', ..] => Some("audio/wav"),
+        [_, _, _, _, b'f', b't', b'y', b'p', brand @ ..] => Some(match brand {
+            [b'q', b't', ..] => "video/quicktime",
+            [b'M', b'4', b'A', ..] => "audio/mp4",
+            _ => "video/mp4",
+        }),
+        [0xff, second, ..] if second & 0xe0 == 0xe0 => Some("audio/mpeg"),
+        _ => None,
+    }
+}